@@ -1,21 +1,30 @@
-use std::io::{self, Write, Read};
+use std::io::{self, Write};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent},
+    cursor,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers, MouseEvent},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    style::Color as RColor,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color as RColor, Modifier, Style},
     text::{Line, Span},
     widgets::{Block, Borders, Paragraph},
     Terminal,
 };
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::fs::File;
 
-type Color = [u8; 3];
+type Color = [u8; 4];
+
+const WHITE: Color = [255, 255, 255, 255];
+const TRANSPARENT: Color = [0, 0, 0, 0];
+
+const RAINT_VERSION: &str = "1.0.1";
+const MAX_CANVAS_SIZE: usize = 4096;
 
 #[derive(Clone)]
 struct Canvas {
@@ -29,15 +38,15 @@ impl Canvas {
         Canvas {
             width,
             height,
-            pixels: vec![[255, 255, 255]; width * height],
+            pixels: vec![WHITE; width * height],
         }
     }
 
-    fn clone_for_preview(&self) -> Self {
+    fn new_transparent(width: usize, height: usize) -> Self {
         Canvas {
-            width: self.width,
-            height: self.height,
-            pixels: self.pixels.clone(),
+            width,
+            height,
+            pixels: vec![TRANSPARENT; width * height],
         }
     }
 
@@ -51,921 +60,7037 @@ impl Canvas {
         if x < self.width && y < self.height {
             self.pixels[y * self.width + x]
         } else {
-            [255, 255, 255]
+            TRANSPARENT
         }
     }
+}
 
-    #[allow(dead_code)]
-    fn render_to_string(&self) -> String {
-        let mut output = String::new();
-        let rows = (self.height + 1) / 2;
-
-        for row in 0..rows {
-            for col in 0..self.width {
-                let top_y = row * 2;
-                let bottom_y = top_y + 1;
-
-                let top_color = self.get_pixel(col, top_y);
-                let bottom_color = if bottom_y < self.height {
-                    self.get_pixel(col, bottom_y)
-                } else {
-                    [255, 255, 255]
-                };
+#[derive(Clone)]
+struct Layer {
+    name: String,
+    visible: bool,
+    opacity: u8,
+    locked: bool,
+    canvas: Canvas,
+}
 
-                output.push_str(&format!(
-                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m▀",
-                    top_color[0], top_color[1], top_color[2],
-                    bottom_color[0], bottom_color[1], bottom_color[2]
-                ));
-            }
-            output.push_str("\x1b[0m\n");
+impl Layer {
+    fn new(name: &str, canvas: Canvas) -> Self {
+        Layer {
+            name: name.to_string(),
+            visible: true,
+            opacity: 100,
+            locked: false,
+            canvas,
         }
+    }
+}
+
+// Layers are stored bottom first; `active` indexes the layer tools paint on
+#[derive(Clone)]
+struct LayerStack {
+    width: usize,
+    height: usize,
+    layers: Vec<Layer>,
+    active: usize,
+    next_layer_number: usize,
+}
 
-        output
+impl LayerStack {
+    fn new(width: usize, height: usize) -> Self {
+        LayerStack::from_canvas(Canvas::new(width, height))
     }
 
-    fn render_to_spans(&self) -> Vec<Line<'static>> {
-        let mut lines = Vec::new();
+    fn from_canvas(canvas: Canvas) -> Self {
+        LayerStack {
+            width: canvas.width,
+            height: canvas.height,
+            layers: vec![Layer::new("Background", canvas)],
+            active: 0,
+            next_layer_number: 1,
+        }
+    }
 
-        for row in 0..self.height {
-            let mut spans = Vec::new();
-            for col in 0..self.width {
-                let color = self.get_pixel(col, row);
-                let fg = RColor::Rgb(color[0], color[1], color[2]);
-                let span = Span::styled("██", ratatui::style::Style::default().fg(fg));
-                spans.push(span);
-            }
-            
-            let reset_span = Span::styled("", ratatui::style::Style::default().fg(RColor::Reset));
-            spans.push(reset_span);
-            
-            lines.push(Line::from(spans));
+    fn from_layers(width: usize, height: usize, layers: Vec<Layer>, active: usize) -> Self {
+        // Continue after the highest "Layer N" so new layers never reuse a loaded name
+        let next_layer_number = layers
+            .iter()
+            .filter_map(|layer| layer.name.strip_prefix("Layer ")?.parse::<usize>().ok())
+            .map(|n| n + 1)
+            .fold(layers.len(), usize::max);
+        LayerStack {
+            width,
+            height,
+            active: active.min(layers.len().saturating_sub(1)),
+            layers,
+            next_layer_number,
         }
+    }
 
-        lines
+    fn active_layer(&self) -> &Layer {
+        &self.layers[self.active]
     }
-}
 
-fn clamp(val: usize, min: usize, max: usize) -> usize {
-    if val < min { min } else if val > max { max } else { val }
-}
+    fn active_layer_mut(&mut self) -> &mut Layer {
+        &mut self.layers[self.active]
+    }
 
-fn draw_line_with_brush(canvas: &mut Canvas, x0: i32, y0: i32, x1: i32, y1: i32, thickness: usize, color: Color) {
-    let dx = (x1 - x0).abs();
-    let dy = (y1 - y0).abs();
-    let sx = if x0 < x1 { 1 } else { -1 };
-    let sy = if y0 < y1 { 1 } else { -1 };
+    fn active_canvas_mut(&mut self) -> &mut Canvas {
+        &mut self.layers[self.active].canvas
+    }
 
-    let mut err = dx - dy;
-    let mut x = x0;
-    let mut y = y0;
+    fn add_layer(&mut self) {
+        let name = format!("Layer {}", self.next_layer_number);
+        self.next_layer_number += 1;
+        let layer = Layer::new(&name, Canvas::new_transparent(self.width, self.height));
+        self.layers.insert(self.active + 1, layer);
+        self.active += 1;
+    }
 
-    loop {
-        if x >= 0 && x < canvas.width as i32 && y >= 0 && y < canvas.height as i32 {
-            draw_brush_stroke(canvas, x as usize, y as usize, thickness, color);
+    fn delete_layer(&mut self) -> bool {
+        if self.layers.len() <= 1 {
+            return false;
         }
+        self.layers.remove(self.active);
+        self.active = self.active.saturating_sub(1);
+        true
+    }
 
-        if x == x1 && y == y1 { break; }
+    fn duplicate_layer(&mut self) {
+        let mut copy = self.active_layer().clone();
+        copy.name = format!("{} copy", copy.name);
+        self.layers.insert(self.active + 1, copy);
+        self.active += 1;
+    }
 
-        let e2 = 2 * err;
-        if e2 > -dy {
-            err -= dy;
-            x += sx;
+    fn merge_down(&mut self) -> bool {
+        if self.active == 0 {
+            return false;
         }
-        if e2 < dx {
-            err += dx;
-            y += sy;
+        let upper = self.layers.remove(self.active);
+        self.active -= 1;
+        let lower = &mut self.layers[self.active].canvas;
+        if upper.visible {
+            for (below, &above) in lower.pixels.iter_mut().zip(&upper.canvas.pixels) {
+                *below = blend_over(*below, above, upper.opacity);
+            }
         }
+        true
     }
-}
 
-fn draw_line(canvas: &mut Canvas, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
-    let dx = (x1 - x0).abs();
-    let dy = (y1 - y0).abs();
-    let sx = if x0 < x1 { 1 } else { -1 };
-    let sy = if y0 < y1 { 1 } else { -1 };
+    fn move_layer_up(&mut self) -> bool {
+        if self.active + 1 >= self.layers.len() {
+            return false;
+        }
+        self.layers.swap(self.active, self.active + 1);
+        self.active += 1;
+        true
+    }
 
-    let mut err = dx - dy;
-    let mut x = x0;
-    let mut y = y0;
+    fn move_layer_down(&mut self) -> bool {
+        if self.active == 0 {
+            return false;
+        }
+        self.layers.swap(self.active, self.active - 1);
+        self.active -= 1;
+        true
+    }
 
-    loop {
-        if x >= 0 && x < canvas.width as i32 && y >= 0 && y < canvas.height as i32 {
-            canvas.set_pixel(x as usize, y as usize, color);
+    fn composite_pixel(&self, x: usize, y: usize) -> Color {
+        let mut out = TRANSPARENT;
+        for layer in self.layers.iter().filter(|l| l.visible) {
+            out = blend_over(out, layer.canvas.get_pixel(x, y), layer.opacity);
         }
+        out
+    }
 
-        if x == x1 && y == y1 { break; }
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+    }
 
-        let e2 = 2 * err;
-        if e2 > -dy {
-            err -= dy;
-            x += sx;
-        }
-        if e2 < dx {
-            err += dx;
-            y += sy;
+    // The eyedropper reads either what is shown or the active layer on its own
+    fn sample(&self, x: usize, y: usize, composite: bool) -> Color {
+        if composite {
+            self.composite_pixel(x, y)
+        } else {
+            self.active_layer().canvas.get_pixel(x, y)
         }
     }
-}
-
-fn draw_circle(canvas: &mut Canvas, cx: i32, cy: i32, radius: i32, color: Color) {
-    let r2 = radius * radius;
 
-    for y in -radius..=radius {
-        for x in -radius..=radius {
-            if x * x + y * y <= r2 {
-                let px = cx + x;
-                let py = cy + y;
-                if px >= 0 && px < canvas.width as i32 && py >= 0 && py < canvas.height as i32 {
-                    canvas.set_pixel(px as usize, py as usize, color);
-                }
+    fn composite(&self) -> Canvas {
+        let mut out = Canvas::new_transparent(self.width, self.height);
+        for layer in self.layers.iter().filter(|l| l.visible) {
+            for (below, &above) in out.pixels.iter_mut().zip(&layer.canvas.pixels) {
+                *below = blend_over(*below, above, layer.opacity);
             }
         }
+        out
     }
 }
 
-fn draw_rectangle(canvas: &mut Canvas, cx: i32, cy: i32, half_size: i32, color: Color) {
-    let x_min = (cx - half_size).max(0) as usize;
-    let x_max = ((cx + half_size).min(canvas.width as i32 - 1) + 1) as usize;
-    let y_min = (cy - half_size).max(0) as usize;
-    let y_max = ((cy + half_size).min(canvas.height as i32 - 1) + 1) as usize;
-
-    for y in y_min..y_max {
-        for x in x_min..x_max {
-            canvas.set_pixel(x, y, color);
-        }
+// Porter-Duff "over" on straight (non-premultiplied) alpha, with the layer
+// opacity percentage scaling the top pixel's alpha
+fn blend_over(below: Color, above: Color, opacity: u8) -> Color {
+    let src_a = above[3] as u32 * opacity.min(100) as u32 / 100;
+    if src_a == 0 {
+        return below;
     }
+    let dst_a = below[3] as u32 * (255 - src_a) / 255;
+    let out_a = src_a + dst_a;
+    let mix = |s: u8, d: u8| ((s as u32 * src_a + d as u32 * dst_a) / out_a) as u8;
+    [mix(above[0], below[0]), mix(above[1], below[1]), mix(above[2], below[2]), out_a as u8]
 }
 
-fn draw_rect_preview(canvas: &mut Canvas, cx: i32, cy: i32, hx: i32, hy: i32, color: Color) {
-    let x_min = (cx - hx).max(0) as usize;
-    let x_max = ((cx + hx).min(canvas.width as i32 - 1) + 1) as usize;
-    let y_min = (cy - hy).max(0) as usize;
-    let y_max = ((cy + hy).min(canvas.height as i32 - 1) + 1) as usize;
+// Undo keeps the last committed state plus per-action deltas against it, rather than a
+// full copy of every layer per action. The oldest steps are dropped once there are more
+// than `max_steps` or they hold more than `budget_bytes` of pixel data.
+const DEFAULT_UNDO_STEPS: usize = 100;
+const DEFAULT_UNDO_MEGABYTES: usize = 64;
 
-    for y in y_min..y_max {
-        for x in x_min..x_max {
-            canvas.set_pixel(x, y, color);
+#[derive(Clone)]
+struct LayerMeta {
+    name: String,
+    visible: bool,
+    opacity: u8,
+    locked: bool,
+}
+
+// Everything about a layer stack except its pixels
+#[derive(Clone)]
+struct StackFrame {
+    width: usize,
+    height: usize,
+    active: usize,
+    next_layer_number: usize,
+    layers: Vec<LayerMeta>,
+}
+
+impl StackFrame {
+    fn of(stack: &LayerStack) -> Self {
+        StackFrame {
+            width: stack.width,
+            height: stack.height,
+            active: stack.active,
+            next_layer_number: stack.next_layer_number,
+            layers: stack
+                .layers
+                .iter()
+                .map(|l| LayerMeta { name: l.name.clone(), visible: l.visible, opacity: l.opacity, locked: l.locked })
+                .collect(),
+        }
+    }
+
+    fn restore(&self, stack: &mut LayerStack) {
+        stack.width = self.width;
+        stack.height = self.height;
+        stack.active = self.active;
+        stack.next_layer_number = self.next_layer_number;
+        for (layer, meta) in stack.layers.iter_mut().zip(&self.layers) {
+            layer.name = meta.name.clone();
+            layer.visible = meta.visible;
+            layer.opacity = meta.opacity;
+            layer.locked = meta.locked;
         }
     }
 }
 
-fn draw_brush_stroke(canvas: &mut Canvas, x: usize, y: usize, thickness: usize, color: Color) {
-    let t = thickness as i32;
-    let x = x as i32;
-    let y = y as i32;
-    
-    for dy in 0..t {
-        for dx in 0..t {
-            let px = x + dx - t / 2;
-            let py = y + dy - t / 2;
-            if px >= 0 && px < canvas.width as i32 && py >= 0 && py < canvas.height as i32 {
-                canvas.set_pixel(px as usize, py as usize, color);
+// The dirty rectangle of one layer, before and after an action
+struct PixelPatch {
+    layer: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    before: Vec<Color>,
+    after: Vec<Color>,
+}
+
+impl PixelPatch {
+    fn between(layer: usize, before: &Canvas, after: &Canvas) -> Option<Self> {
+        let w = before.width;
+        let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+        for (i, (a, b)) in before.pixels.iter().zip(&after.pixels).enumerate() {
+            if a != b {
+                let (x, y) = (i % w, i / w);
+                x0 = x0.min(x);
+                y0 = y0.min(y);
+                x1 = x1.max(x);
+                y1 = y1.max(y);
             }
         }
+        if x0 == usize::MAX {
+            return None;
+        }
+
+        let copy = |canvas: &Canvas| -> Vec<Color> {
+            (y0..=y1).flat_map(|y| canvas.pixels[y * w + x0..=y * w + x1].iter().copied()).collect()
+        };
+        Some(PixelPatch { layer, x: x0, y: y0, width: x1 - x0 + 1, before: copy(before), after: copy(after) })
+    }
+
+    fn write(&self, canvas: &mut Canvas, pixels: &[Color]) {
+        for (row, chunk) in pixels.chunks(self.width).enumerate() {
+            let start = (self.y + row) * canvas.width + self.x;
+            canvas.pixels[start..start + self.width].copy_from_slice(chunk);
+        }
     }
 }
 
-fn flood_fill(canvas: &mut Canvas, x: i32, y: i32, new_color: Color) {
-    let w = canvas.width as i32;
-    let h = canvas.height as i32;
-    if x < 0 || y < 0 || x >= w || y >= h {
-        return;
+enum HistoryChange {
+    // Same size and layer count: only the dirty rectangles changed
+    Pixels(Vec<PixelPatch>),
+    // Layers added, removed, reordered or resized: the run of layers between the
+    // unchanged ones below and above, before and after
+    Splice { at: usize, before: Vec<Layer>, after: Vec<Layer> },
+}
+
+struct HistoryStep {
+    before: StackFrame,
+    after: StackFrame,
+    change: HistoryChange,
+}
+
+impl HistoryStep {
+    fn between(before: &LayerStack, after: &LayerStack) -> Self {
+        let change = if before.width == after.width && before.height == after.height && before.layers.len() == after.layers.len() {
+            HistoryChange::Pixels(
+                before
+                    .layers
+                    .iter()
+                    .zip(&after.layers)
+                    .enumerate()
+                    .filter_map(|(i, (a, b))| PixelPatch::between(i, &a.canvas, &b.canvas))
+                    .collect(),
+            )
+        } else {
+            let same = |(a, b): &(&Layer, &Layer)| {
+                a.canvas.width == b.canvas.width && a.canvas.height == b.canvas.height && a.canvas.pixels == b.canvas.pixels
+            };
+            let prefix = before.layers.iter().zip(&after.layers).take_while(same).count();
+            let room = before.layers.len().min(after.layers.len()) - prefix;
+            let suffix = before.layers.iter().rev().zip(after.layers.iter().rev()).take(room).take_while(same).count();
+            HistoryChange::Splice {
+                at: prefix,
+                before: before.layers[prefix..before.layers.len() - suffix].to_vec(),
+                after: after.layers[prefix..after.layers.len() - suffix].to_vec(),
+            }
+        };
+        HistoryStep { before: StackFrame::of(before), after: StackFrame::of(after), change }
     }
 
-    let tx = x as usize;
-    let ty = y as usize;
-    let target = canvas.get_pixel(tx, ty);
-    if target == new_color {
-        return;
+    fn bytes(&self) -> usize {
+        let pixel = std::mem::size_of::<Color>();
+        let frames = (self.before.layers.len() + self.after.layers.len()) * std::mem::size_of::<LayerMeta>();
+        frames
+            + match &self.change {
+                HistoryChange::Pixels(patches) => patches.iter().map(|p| (p.before.len() + p.after.len()) * pixel).sum(),
+                HistoryChange::Splice { before, after, .. } => {
+                    before.iter().chain(after).map(|l| l.canvas.pixels.len() * pixel).sum::<usize>()
+                }
+            }
     }
 
-    let mut stack = Vec::new();
-    stack.push((x, y));
+    // Whether `stack` is exactly the state this step starts from in that direction;
+    // history read from a file is checked with this before any of it is applied
+    fn fits(&self, stack: &LayerStack, forward: bool) -> bool {
+        let frame = if forward { &self.before } else { &self.after };
+        let same_frame = frame.width == stack.width
+            && frame.height == stack.height
+            && frame.layers.len() == stack.layers.len()
+            && frame.layers.iter().zip(&stack.layers).all(|(meta, layer)| {
+                meta.name == layer.name && meta.visible == layer.visible && meta.opacity == layer.opacity && meta.locked == layer.locked
+            });
+        same_frame
+            && match &self.change {
+                HistoryChange::Pixels(patches) => patches.iter().all(|patch| {
+                    let pixels = if forward { &patch.before } else { &patch.after };
+                    stack.layers.get(patch.layer).is_some_and(|layer| {
+                        let canvas = &layer.canvas;
+                        patch.x + patch.width <= canvas.width
+                            && patch.y + pixels.len() / patch.width <= canvas.height
+                            && pixels.chunks(patch.width).enumerate().all(|(row, chunk)| {
+                                let start = (patch.y + row) * canvas.width + patch.x;
+                                canvas.pixels[start..start + patch.width] == *chunk
+                            })
+                    })
+                }),
+                HistoryChange::Splice { at, before, after } => {
+                    let from = if forward { before } else { after };
+                    stack.layers.get(*at..*at + from.len()).is_some_and(|run| {
+                        run.iter().zip(from).all(|(a, b)| {
+                            a.canvas.width == b.canvas.width && a.canvas.height == b.canvas.height && a.canvas.pixels == b.canvas.pixels
+                        })
+                    })
+                }
+            }
+    }
 
-    while let Some((cx, cy)) = stack.pop() {
-        if cx < 0 || cy < 0 || cx >= w || cy >= h {
-            continue;
-        }
-        let ux = cx as usize;
-        let uy = cy as usize;
-        if canvas.get_pixel(ux, uy) != target {
-            continue;
+    fn apply(&self, stack: &mut LayerStack, forward: bool) {
+        match &self.change {
+            HistoryChange::Pixels(patches) => {
+                for patch in patches {
+                    let pixels = if forward { &patch.after } else { &patch.before };
+                    patch.write(&mut stack.layers[patch.layer].canvas, pixels);
+                }
+            }
+            HistoryChange::Splice { at, before, after } => {
+                let (from, to) = if forward { (before, after) } else { (after, before) };
+                stack.layers.splice(*at..*at + from.len(), to.iter().cloned());
+            }
         }
-        canvas.set_pixel(ux, uy, new_color);
-        stack.push((cx + 1, cy));
-        stack.push((cx - 1, cy));
-        stack.push((cx, cy + 1));
-        stack.push((cx, cy - 1));
+        if forward { &self.after } else { &self.before }.restore(stack);
     }
 }
 
-fn prompt(msg: &str) -> String {
-    disable_raw_mode().ok();
-    print!("{}", msg);
-    let _ = io::stdout().flush();
-
-    let mut input = String::new();
-    let _ = io::stdin().read_line(&mut input);
-
-    enable_raw_mode().ok();
-    input.trim().to_string()
+// Undo history is a tree: undoing and then drawing starts a new branch instead of
+// discarding the redo steps, and any node can be jumped to from the history panel.
+struct HistoryNode {
+    parent: Option<usize>,
+    children: Vec<usize>,
+    // Child that redo follows: the one most recently created or undone from
+    redo_child: Option<usize>,
+    // Delta from the parent; None only for the root
+    step: Option<HistoryStep>,
+    label: String,
+    created: std::time::SystemTime,
 }
 
-fn clear_input_buffer() {
-    while event::poll(Duration::from_millis(0)).ok().unwrap_or(false) {
-        let _ = event::read();
-    }
+// One line of the history panel
+struct HistoryRow {
+    id: usize,
+    depth: usize,
+    branch: bool,
+    label: String,
+    age: Duration,
 }
 
-fn expand_path(path: &str) -> String {
-    if path.starts_with('~') {
-        if let Ok(home) = std::env::var("HOME") {
-            return path.replacen('~', &home, 1);
-        }
-    }
-    path.to_string()
+struct History {
+    // Keyed by creation order, so iteration visits the oldest nodes first
+    nodes: BTreeMap<usize, HistoryNode>,
+    root: usize,
+    current: usize,
+    next_id: usize,
+    // The layer stack at `current`
+    state: LayerStack,
+    bytes: usize,
+    max_steps: usize,
+    budget_bytes: usize,
 }
 
-fn save_canvas(canvas: &Canvas, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let expanded_path = expand_path(filename);
-    
-    if let Some(parent) = std::path::Path::new(&expanded_path).parent() {
-        if !parent.as_os_str().is_empty() {
-            std::fs::create_dir_all(parent)?;
+impl History {
+    fn new(layers: &LayerStack, max_steps: usize, budget_bytes: usize) -> Self {
+        let root = HistoryNode {
+            parent: None,
+            children: Vec::new(),
+            redo_child: None,
+            step: None,
+            label: "Start".to_string(),
+            created: std::time::SystemTime::now(),
+        };
+        History {
+            nodes: BTreeMap::from([(0, root)]),
+            root: 0,
+            current: 0,
+            next_id: 1,
+            state: layers.clone(),
+            bytes: 0,
+            max_steps,
+            budget_bytes,
         }
     }
-    
-    let mut file = File::create(&expanded_path)?;
-    
-    file.write_all(&(canvas.width as u32).to_le_bytes())?;
-    file.write_all(&(canvas.height as u32).to_le_bytes())?;
-    
-    for pixel in &canvas.pixels {
-        file.write_all(&[pixel[0], pixel[1], pixel[2]])?;
-    }
-    
-    Ok(())
-}
 
-fn load_canvas(filename: &str) -> Result<Canvas, Box<dyn std::error::Error>> {
-    let expanded_path = expand_path(filename);
-    let mut file = File::open(&expanded_path)?;
-    
-    let mut width_bytes = [0u8; 4];
-    let mut height_bytes = [0u8; 4];
-    
-    file.read_exact(&mut width_bytes)?;
-    file.read_exact(&mut height_bytes)?;
-    
-    let width = u32::from_le_bytes(width_bytes) as usize;
-    let height = u32::from_le_bytes(height_bytes) as usize;
-    
-    let mut pixels = vec![[255u8, 255u8, 255u8]; width * height];
-    
-    for pixel in &mut pixels {
-        let mut rgb = [0u8; 3];
-        file.read_exact(&mut rgb)?;
-        *pixel = rgb;
-    }
-    
-    Ok(Canvas { width, height, pixels })
-}
+    // Records everything that changed since the last commit as one undo step
+    fn commit(&mut self, layers: &LayerStack, label: &str) {
+        let step = HistoryStep::between(&self.state, layers);
+        self.state = layers.clone();
+        self.bytes += step.bytes();
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    std::process::Command::new("clear").status()?;
-    
-    println!("\n╔════════════════════════════════════════╗");
-    println!("║      Raint - v.1.0.1                  ║");
-    println!("╚════════════════════════════════════════╝\n");
-    println!("Enter canvas size (width height) or single number for square.");
-    println!("Range: 2-80 pixels\nExamples: '40' or '80 40'\n");
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(
+            id,
+            HistoryNode {
+                parent: Some(self.current),
+                children: Vec::new(),
+                redo_child: None,
+                step: Some(step),
+                label: label.to_string(),
+                created: std::time::SystemTime::now(),
+            },
+        );
+        if let Some(parent) = self.nodes.get_mut(&self.current) {
+            parent.children.push(id);
+            parent.redo_child = Some(id);
+        }
+        self.current = id;
+        self.prune();
+    }
 
-    let mut width: usize = 40;
-    let mut height: usize = 40;
+    // Drops the oldest steps until within both limits: either a leaf off the current
+    // position, or the root's only step (its child becomes the new root). The step
+    // that led to `current` is always kept.
+    fn prune(&mut self) {
+        while self.nodes.len() - 1 > self.max_steps || self.bytes > self.budget_bytes {
+            let root = &self.nodes[&self.root];
+            let root_step = match root.children[..] {
+                [child] if self.root != self.current && child != self.current => Some(child),
+                _ => None,
+            };
+            let leaf = self
+                .nodes
+                .iter()
+                .find(|(id, node)| node.children.is_empty() && node.parent.is_some() && **id != self.current)
+                .map(|(id, _)| *id);
 
-    loop {
-        print!("Size> ");
-        let _ = io::stdout().flush();
+            match (root_step, leaf) {
+                (Some(child), leaf) if leaf.is_none_or(|leaf| child < leaf) => {
+                    self.nodes.remove(&self.root);
+                    let node = self.nodes.get_mut(&child).expect("child of the root");
+                    node.parent = None;
+                    self.bytes -= node.step.take().map_or(0, |s| s.bytes());
+                    self.root = child;
+                }
+                (_, Some(leaf)) => {
+                    let node = self.nodes.remove(&leaf).expect("leaf node");
+                    self.bytes -= node.step.map_or(0, |s| s.bytes());
+                    if let Some(parent) = node.parent.and_then(|p| self.nodes.get_mut(&p)) {
+                        parent.children.retain(|&c| c != leaf);
+                        if parent.redo_child == Some(leaf) {
+                            parent.redo_child = parent.children.last().copied();
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
 
-        let mut input = String::new();
-        if io::stdin().read_line(&mut input).is_err() {
-            return Ok(());
+    fn undo(&mut self) -> Option<&LayerStack> {
+        let node = &self.nodes[&self.current];
+        let parent = node.parent?;
+        if let Some(step) = &node.step {
+            step.apply(&mut self.state, false);
         }
+        let child = self.current;
+        if let Some(parent_node) = self.nodes.get_mut(&parent) {
+            parent_node.redo_child = Some(child);
+        }
+        self.current = parent;
+        Some(&self.state)
+    }
 
-        let parts: Vec<&str> = input.trim().split_whitespace().collect();
+    fn redo(&mut self) -> Option<&LayerStack> {
+        let child = self.nodes[&self.current].redo_child?;
+        if let Some(step) = &self.nodes[&child].step {
+            step.apply(&mut self.state, true);
+        }
+        self.current = child;
+        Some(&self.state)
+    }
 
-        if parts.is_empty() {
-            continue;
+    // Nodes whose steps are undone, then redone, to get from `current` to `target`
+    // through their common ancestor
+    fn route(&self, target: usize) -> Option<(Vec<usize>, Vec<usize>)> {
+        let mut chain = vec![target];
+        while let Some(parent) = self.nodes.get(chain.last()?)?.parent {
+            chain.push(parent);
+        }
+        let mut up = Vec::new();
+        let mut node = self.current;
+        while !chain.contains(&node) {
+            up.push(node);
+            node = self.nodes[&node].parent?;
         }
+        let meet = chain.iter().position(|&id| id == node)?;
+        let down = chain[..meet].iter().rev().copied().collect();
+        Some((up, down))
+    }
 
-        if parts.len() == 1 {
-            if let Ok(n) = parts[0].parse::<usize>() {
-                let n = clamp(n, 2, 80);
-                width = n;
-                height = n;
-                break;
+    // Visits every node from `current`, checking that each step fits the state it is
+    // reached from. Needs a well-formed tree: valid ids and no parent loops.
+    fn consistent(&self) -> bool {
+        let neighbours = |id: usize| {
+            let node = &self.nodes[&id];
+            node.children.iter().copied().chain(node.parent)
+        };
+        let mut state = self.state.clone();
+        let mut moves: Vec<(usize, usize, bool)> = neighbours(self.current).map(|n| (self.current, n, true)).collect();
+        while let Some((from, to, explore)) = moves.pop() {
+            let (node, forward) = if self.nodes[&from].parent == Some(to) { (from, false) } else { (to, true) };
+            if let Some(step) = &self.nodes[&node].step {
+                if !step.fits(&state, forward) {
+                    return false;
+                }
+                step.apply(&mut state, forward);
             }
-        } else if parts.len() >= 2 {
-            if let (Ok(w), Ok(h)) = (parts[0].parse::<usize>(), parts[1].parse::<usize>()) {
-                width = clamp(w, 2, 80);
-                height = clamp(h, 2, 80);
-                break;
+            if explore {
+                // Come back once everything beyond `to` has been visited
+                moves.push((to, from, false));
+                moves.extend(neighbours(to).filter(|&n| n != from).map(|n| (to, n, true)));
             }
         }
+        true
+    }
 
-        println!("Invalid input. Try again.");
+    // The layer stack at `target`, leaving the history where it is
+    fn preview(&self, target: usize) -> Option<LayerStack> {
+        let (up, down) = self.route(target)?;
+        let mut state = self.state.clone();
+        for (ids, forward) in [(&up, false), (&down, true)] {
+            for step in ids.iter().filter_map(|id| self.nodes[id].step.as_ref()) {
+                step.apply(&mut state, forward);
+            }
+        }
+        Some(state)
     }
 
-    let mut canvas = Canvas::new(width, height);
-    let mut canvas_history: Vec<Canvas> = vec![canvas.clone_for_preview()];
-    let mut history_index = 0;
-    let mut current_color: Color = [0, 0, 0];
-    let mut brush_thickness: usize = 1;
+    // Moves to `target`, making the path to it the redo branch
+    fn jump(&mut self, target: usize) -> Option<&LayerStack> {
+        let (up, down) = self.route(target)?;
+        for _ in &up {
+            self.undo();
+        }
+        for child in down {
+            if let Some(node) = self.nodes.get_mut(&self.current) {
+                node.redo_child = Some(child);
+            }
+            self.redo();
+        }
+        Some(&self.state)
+    }
 
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
+    // Depth-first, oldest child first; later children are indented as branches
+    fn rows(&self) -> Vec<HistoryRow> {
+        let mut rows = Vec::new();
+        let mut stack = vec![(self.root, 0, false)];
+        while let Some((id, depth, branch)) = stack.pop() {
+            let node = &self.nodes[&id];
+            rows.push(HistoryRow { id, depth, branch, label: node.label.clone(), age: node.created.elapsed().unwrap_or_default() });
+            for (i, &child) in node.children.iter().enumerate().rev() {
+                stack.push((child, if i == 0 { depth } else { depth + 1 }, i > 0));
+            }
+        }
+        rows
+    }
+}
 
-    let backend = CrosstermBackend::new(io::stdout());
-    let mut terminal = Terminal::new(backend)?;
-    terminal.clear()?;
+// Undo tree serialisation for the HIST chunk, all integers little endian:
+//   version:u8 root:u32 current:u32 next_id:u32 node_count:u32, then per node (oldest first)
+//   id:u32 parent:u32 redo_child:u32 (u32::MAX for none) created:u64 (unix seconds)
+//   label_len:u16 label has_step:u8 [step]
+// A step is its before and after frames, then kind:u8 with 0 = pixel patches
+//   (count:u32, each layer:u32 x:u32 y:u32 width:u32 len:u32 before[len] after[len] RGBA)
+//   or 1 = layer splice (at:u32, before and after as count:u32 then whole layers).
+const HISTORY_FORMAT_VERSION: u8 = 1;
+const HISTORY_NONE: u32 = u32::MAX;
 
-    'main_loop: loop {
-        terminal.draw(|f| {
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .margin(0)
-                .constraints([Constraint::Min(1), Constraint::Length(2)])
-                .split(f.size());
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
 
-            let canvas_spans = canvas.render_to_spans();
-            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
-            f.render_widget(canvas_widget, chunks[0]);
+impl<'a> ByteReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], RaiError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(RaiError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
 
-            let info_text = format!(
-                "H - Help | Color: RGB({}, {}, {}) | Thickness: {}",
-                current_color[0], current_color[1], current_color[2], brush_thickness
-            );
-            let info_widget = Paragraph::new(info_text).block(Block::default().borders(Borders::TOP));
-            f.render_widget(info_widget, chunks[1]);
-        })?;
+    fn u8(&mut self) -> Result<u8, RaiError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, RaiError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, RaiError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, RaiError> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    fn string(&mut self) -> Result<String, RaiError> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    fn pixels(&mut self, count: usize) -> Result<Vec<Color>, RaiError> {
+        Ok(pixels_from_rgba(self.bytes(count.checked_mul(4).ok_or(RaiError::Truncated)?)?))
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    let bytes = &s.as_bytes()[..s.len().min(u16::MAX as usize)];
+    out.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_pixels(out: &mut Vec<u8>, pixels: &[Color]) {
+    out.extend(pixels.iter().flat_map(|p| p.iter().copied()));
+}
+
+fn layer_flags(visible: bool, locked: bool) -> u8 {
+    (if visible { RAI_LAYER_VISIBLE } else { 0 }) | (if locked { RAI_LAYER_LOCKED } else { 0 })
+}
+
+fn put_frame(out: &mut Vec<u8>, frame: &StackFrame) {
+    for n in [frame.width, frame.height, frame.active, frame.next_layer_number, frame.layers.len()] {
+        out.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+    for meta in &frame.layers {
+        out.push(layer_flags(meta.visible, meta.locked));
+        out.push(meta.opacity);
+        put_string(out, &meta.name);
+    }
+}
+
+fn read_frame(r: &mut ByteReader) -> Result<StackFrame, RaiError> {
+    let (width, height) = (r.u32()? as usize, r.u32()? as usize);
+    check_rai_dimensions(width, height)?;
+    let (active, next_layer_number, count) = (r.u32()? as usize, r.u32()? as usize, r.u32()? as usize);
+    let mut layers = Vec::new();
+    for _ in 0..count {
+        let flags = r.u8()?;
+        let opacity = r.u8()?.min(100);
+        let name = r.string()?;
+        layers.push(LayerMeta { name, visible: flags & RAI_LAYER_VISIBLE != 0, opacity, locked: flags & RAI_LAYER_LOCKED != 0 });
+    }
+    Ok(StackFrame { width, height, active, next_layer_number, layers })
+}
+
+fn put_layers(out: &mut Vec<u8>, layers: &[Layer]) {
+    out.extend_from_slice(&(layers.len() as u32).to_le_bytes());
+    for layer in layers {
+        out.push(layer_flags(layer.visible, layer.locked));
+        out.push(layer.opacity);
+        put_string(out, &layer.name);
+        out.extend_from_slice(&(layer.canvas.width as u32).to_le_bytes());
+        out.extend_from_slice(&(layer.canvas.height as u32).to_le_bytes());
+        put_pixels(out, &layer.canvas.pixels);
+    }
+}
+
+fn read_layers(r: &mut ByteReader) -> Result<Vec<Layer>, RaiError> {
+    let count = r.u32()?;
+    let mut layers = Vec::new();
+    for _ in 0..count {
+        let flags = r.u8()?;
+        let opacity = r.u8()?.min(100);
+        let name = r.string()?;
+        let (width, height) = (r.u32()? as usize, r.u32()? as usize);
+        check_rai_dimensions(width, height)?;
+        let canvas = Canvas { width, height, pixels: r.pixels(width * height)? };
+        layers.push(Layer { name, visible: flags & RAI_LAYER_VISIBLE != 0, opacity, locked: flags & RAI_LAYER_LOCKED != 0, canvas });
+    }
+    Ok(layers)
+}
+
+fn encode_history(history: &History) -> Vec<u8> {
+    let mut out = vec![HISTORY_FORMAT_VERSION];
+    for n in [history.root, history.current, history.next_id, history.nodes.len()] {
+        out.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+
+    for (&id, node) in &history.nodes {
+        let parent = node.parent.map_or(HISTORY_NONE, |p| p as u32);
+        let redo_child = node.redo_child.map_or(HISTORY_NONE, |c| c as u32);
+        let created = node.created.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        out.extend_from_slice(&(id as u32).to_le_bytes());
+        out.extend_from_slice(&parent.to_le_bytes());
+        out.extend_from_slice(&redo_child.to_le_bytes());
+        out.extend_from_slice(&created.to_le_bytes());
+        put_string(&mut out, &node.label);
+
+        let Some(step) = &node.step else {
+            out.push(0);
+            continue;
+        };
+        out.push(1);
+        put_frame(&mut out, &step.before);
+        put_frame(&mut out, &step.after);
+        match &step.change {
+            HistoryChange::Pixels(patches) => {
+                out.push(0);
+                out.extend_from_slice(&(patches.len() as u32).to_le_bytes());
+                for patch in patches {
+                    for n in [patch.layer, patch.x, patch.y, patch.width, patch.before.len()] {
+                        out.extend_from_slice(&(n as u32).to_le_bytes());
+                    }
+                    put_pixels(&mut out, &patch.before);
+                    put_pixels(&mut out, &patch.after);
+                }
+            }
+            HistoryChange::Splice { at, before, after } => {
+                out.push(1);
+                out.extend_from_slice(&(*at as u32).to_le_bytes());
+                put_layers(&mut out, before);
+                put_layers(&mut out, after);
+            }
+        }
+    }
+    out
+}
+
+fn read_history_step(r: &mut ByteReader) -> Result<HistoryStep, RaiError> {
+    let before = read_frame(r)?;
+    let after = read_frame(r)?;
+    let change = match r.u8()? {
+        0 => {
+            let count = r.u32()?;
+            let mut patches = Vec::new();
+            for _ in 0..count {
+                let (layer, x, y, width, len) = (r.u32()? as usize, r.u32()? as usize, r.u32()? as usize, r.u32()? as usize, r.u32()? as usize);
+                if width == 0 || len % width != 0 || layer >= before.layers.len() || x + width > before.width || y + len / width > before.height {
+                    return Err(RaiError::Corrupt("history patch out of bounds".into()));
+                }
+                patches.push(PixelPatch { layer, x, y, width, before: r.pixels(len)?, after: r.pixels(len)? });
+            }
+            HistoryChange::Pixels(patches)
+        }
+        1 => {
+            let at = r.u32()? as usize;
+            HistoryChange::Splice { at, before: read_layers(r)?, after: read_layers(r)? }
+        }
+        kind => return Err(RaiError::Corrupt(format!("unknown history step kind {}", kind))),
+    };
+    Ok(HistoryStep { before, after, change })
+}
+
+// Rebuilds the undo tree saved with `layers`. History that does not end at this
+// exact layer layout (say, the file was edited elsewhere) is rejected.
+fn decode_history(body: &[u8], layers: &LayerStack, max_steps: usize, budget_bytes: usize) -> Result<History, RaiError> {
+    let raw_len = read_u32_le(body, 0)? as usize;
+    // Room for labels and layer metadata on top of the pixel data, and for a file
+    // saved with a somewhat larger undo budget; anything bigger is not decompressed
+    let limit = budget_bytes.saturating_mul(2).saturating_add(1 << 20);
+    if raw_len > limit {
+        return Err(RaiError::Corrupt(format!("history is {} bytes, more than the undo budget allows", raw_len)));
+    }
+    let raw = zlib_decompress(&body[4..], raw_len).map_err(RaiError::Corrupt)?;
+    let mut r = ByteReader { data: &raw, pos: 0 };
+
+    let version = r.u8()?;
+    if version != HISTORY_FORMAT_VERSION {
+        return Err(RaiError::UnsupportedFormat(format!("history version {}", version)));
+    }
+    let (root, current, mut next_id, count) = (r.u32()? as usize, r.u32()? as usize, r.u32()? as usize, r.u32()?);
+
+    let mut nodes = BTreeMap::new();
+    for _ in 0..count {
+        let id = r.u32()? as usize;
+        let parent = Some(r.u32()?).filter(|&p| p != HISTORY_NONE).map(|p| p as usize);
+        let redo_child = Some(r.u32()?).filter(|&c| c != HISTORY_NONE).map(|c| c as usize);
+        let created = std::time::UNIX_EPOCH + Duration::from_secs(r.u64()?);
+        let label = r.string()?;
+        let step = if r.u8()? != 0 { Some(read_history_step(&mut r)?) } else { None };
+        nodes.insert(id, HistoryNode { parent, children: Vec::new(), redo_child, step, label, created });
+    }
+
+    let corrupt = |message: &str| RaiError::Corrupt(message.into());
+    if nodes.get(&root).is_none_or(|n| n.parent.is_some()) || !nodes.contains_key(&current) {
+        return Err(corrupt("history root or current node is missing"));
+    }
+    let links: Vec<(usize, usize)> = nodes.iter().filter_map(|(&id, n)| n.parent.map(|p| (p, id))).collect();
+    for (parent, child) in links {
+        nodes.get_mut(&parent).ok_or_else(|| corrupt("history node has no parent"))?.children.push(child);
+    }
+    // Every node has to lead back to the root; a parent loop would hang `route`
+    let mut rooted = std::collections::BTreeSet::from([root]);
+    for &id in nodes.keys() {
+        let mut path = Vec::new();
+        let mut node = id;
+        while !rooted.contains(&node) {
+            if path.len() > nodes.len() {
+                return Err(corrupt("history has a parent loop"));
+            }
+            path.push(node);
+            node = nodes[&node].parent.ok_or_else(|| corrupt("history has more than one root"))?;
+        }
+        rooted.extend(path);
+    }
+    if nodes.iter().any(|(&id, n)| n.redo_child.is_some_and(|c| nodes.get(&c).is_none_or(|c| c.parent != Some(id)))) {
+        return Err(corrupt("history redo link points outside its node"));
+    }
+    // New commits must not reuse a saved id
+    next_id = next_id.max(nodes.keys().last().map_or(0, |&id| id + 1));
+
+    let bytes = nodes.values().filter_map(|n| n.step.as_ref()).map(|s| s.bytes()).sum();
+    let mut history = History { nodes, root, current, next_id, state: layers.clone(), bytes, max_steps, budget_bytes };
+    if !history.consistent() {
+        return Err(corrupt("history does not match the saved image"));
+    }
+    history.prune();
+    Ok(history)
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+fn history_panel(history: &History, rows: &[HistoryRow], selected: usize, height: u16) -> Paragraph<'static> {
+    let mut lines = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let text = format!(
+            "{}{}{}{} {}",
+            if row.id == history.current { ">" } else { " " },
+            "  ".repeat(row.depth),
+            if row.branch { "\u{2514} " } else { "" },
+            row.label,
+            format_age(row.age)
+        );
+        let style = if i == selected {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        lines.push(Line::from(Span::styled(text, style)));
+    }
+    let visible = height.saturating_sub(2) as usize;
+    let scroll = (selected + 1).saturating_sub(visible) as u16;
+    Paragraph::new(lines).scroll((scroll, 0)).block(Block::default().borders(Borders::ALL).title(" History "))
+}
+
+const LAYER_PANEL_WIDTH: u16 = 30;
+
+// Canvas on the left; layers above the palette on the right
+fn split_canvas_area(area: Rect, palette: &Palette) -> [Rect; 3] {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(1), Constraint::Length(LAYER_PANEL_WIDTH)])
+        .split(area);
+    let side = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(palette.panel_height())])
+        .split(columns[1]);
+    [columns[0], side[0], side[1]]
+}
+
+fn layer_panel(layers: &LayerStack) -> Paragraph<'static> {
+    let mut lines = Vec::new();
+    for (i, layer) in layers.layers.iter().enumerate().rev() {
+        let text = format!(
+            "{} {} {} {:>3}% {}",
+            if i == layers.active { ">" } else { " " },
+            if layer.visible { "[v]" } else { "[ ]" },
+            if layer.locked { "L" } else { " " },
+            layer.opacity,
+            layer.name
+        );
+        let style = if i == layers.active {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+        };
+        lines.push(Line::from(Span::styled(text, style)));
+    }
+    Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Layers "))
+}
+
+// DawnBringer's 32 colour palette
+const DEFAULT_PALETTE: [u32; 32] = [
+    0x000000, 0x222034, 0x45283c, 0x663931, 0x8f563b, 0xdf7126, 0xd9a066, 0xeec39a,
+    0xfbf236, 0x99e550, 0x6abe30, 0x37946e, 0x4b692f, 0x524b24, 0x323c39, 0x3f3f74,
+    0x306082, 0x5b6ee1, 0x639bff, 0x5fcde4, 0xcbdbfc, 0xffffff, 0x9badb7, 0x847e87,
+    0x696a6a, 0x595652, 0x76428a, 0xac3232, 0xd95763, 0xd77bba, 0x8f974a, 0x8a6f30,
+];
+const SWATCH_WIDTH: u16 = 3;
+const PALETTE_COLUMNS: usize = ((LAYER_PANEL_WIDTH - 2) / SWATCH_WIDTH) as usize;
+const PALETTE_MAX_ROWS: usize = 8;
+
+struct Palette {
+    colors: Vec<Color>,
+    selected: usize,
+    // Where the panel was last drawn and its first visible swatch row, for mouse hits
+    area: Rect,
+    first_row: usize,
+}
+
+impl Palette {
+    fn new() -> Self {
+        let colors = DEFAULT_PALETTE
+            .iter()
+            .map(|rgb| [(rgb >> 16) as u8, (rgb >> 8) as u8, *rgb as u8, 255])
+            .collect();
+        Palette::from_colors(colors)
+    }
+
+    fn from_colors(colors: Vec<Color>) -> Self {
+        Palette { colors, selected: 0, area: Rect::default(), first_row: 0 }
+    }
+
+    fn rows(&self) -> usize {
+        self.colors.len().div_ceil(PALETTE_COLUMNS)
+    }
+
+    // Borders, the colour line, then up to PALETTE_MAX_ROWS rows of swatches
+    fn panel_height(&self) -> u16 {
+        (self.rows().min(PALETTE_MAX_ROWS) + 3) as u16
+    }
+
+    fn step(&mut self, delta: i32) -> Color {
+        let len = self.colors.len() as i32;
+        self.selected = (self.selected as i32 + delta).rem_euclid(len) as usize;
+        self.colors[self.selected]
+    }
+
+    fn add(&mut self, color: Color) {
+        self.selected = (self.selected + 1).min(self.colors.len());
+        self.colors.insert(self.selected, color);
+    }
+
+    fn replace_selected(&mut self, color: Color) {
+        self.colors[self.selected] = color;
+    }
+
+    // The last swatch stays so there is always something to pick
+    fn remove_selected(&mut self) {
+        if self.colors.len() > 1 {
+            self.colors.remove(self.selected);
+            self.selected = self.selected.min(self.colors.len() - 1);
+        }
+    }
+
+    fn swatch_at(&self, column: u16, row: u16) -> Option<usize> {
+        let x = column.checked_sub(self.area.x + 1)? / SWATCH_WIDTH;
+        let y = row.checked_sub(self.area.y + 2)?;
+        if x as usize >= PALETTE_COLUMNS || y + 4 > self.area.height {
+            return None;
+        }
+        let index = (self.first_row + y as usize) * PALETTE_COLUMNS + x as usize;
+        (index < self.colors.len()).then_some(index)
+    }
+
+    // Left click picks the colour, right click the secondary colour; `,` and `.` step
+    // through the swatches and `;` swaps the two colours.
+    fn handle_event(&mut self, event: &Event, color: &mut Color, secondary: &mut Color) -> bool {
+        use crossterm::event::{MouseButton, MouseEventKind};
+
+        match event {
+            Event::Key(KeyEvent { code: KeyCode::Char(','), .. }) => *color = self.step(-1),
+            Event::Key(KeyEvent { code: KeyCode::Char('.'), .. }) => *color = self.step(1),
+            Event::Key(KeyEvent { code: KeyCode::Char(';'), .. }) => std::mem::swap(color, secondary),
+            Event::Mouse(mouse_event) => {
+                let area = self.area;
+                let inside = mouse_event.column >= area.x
+                    && mouse_event.column < area.x + area.width
+                    && mouse_event.row >= area.y
+                    && mouse_event.row < area.y + area.height;
+                if !inside {
+                    return false;
+                }
+                // Clicks on the panel never reach the tool underneath, even between swatches
+                if let Some(index) = self.swatch_at(mouse_event.column, mouse_event.row) {
+                    match mouse_event.kind {
+                        MouseEventKind::Down(MouseButton::Left) => {
+                            self.selected = index;
+                            *color = self.colors[index];
+                        }
+                        MouseEventKind::Down(MouseButton::Right) => *secondary = self.colors[index],
+                        _ => {}
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn panel(&mut self, color: Color, secondary: Color) -> Paragraph<'static> {
+        let visible_rows = (self.area.height as usize).saturating_sub(3).max(1);
+        let selected_row = self.selected / PALETTE_COLUMNS;
+        self.first_row = self.first_row.min(selected_row).max((selected_row + 1).saturating_sub(visible_rows));
+
+        let swatch = |c: Color, text: &str| color_cell(c, 0, 0, text);
+        let mut lines = vec![Line::from(vec![
+            Span::raw("Colour "),
+            swatch(color, "  "),
+            Span::raw(" Secondary "),
+            swatch(secondary, "  "),
+            Span::raw(format!(" {}/{}", self.selected + 1, self.colors.len())),
+        ])];
+        for row in self.first_row..(self.first_row + visible_rows).min(self.rows()) {
+            let start = row * PALETTE_COLUMNS;
+            let end = (start + PALETTE_COLUMNS).min(self.colors.len());
+            let spans: Vec<Span> = (start..end)
+                .map(|i| swatch(self.colors[i], if i == self.selected { "[ ]" } else { "   " }))
+                .collect();
+            lines.push(Line::from(spans));
+        }
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Palette "))
+    }
+}
+
+// Palette files: GIMP .gpl, JASC-PAL .pal and .hex (one RRGGBB or RRGGBBAA per line).
+// Only .hex keeps alpha; the other formats load as opaque.
+const PALETTE_MAX_COLORS: usize = 256;
+
+#[derive(Debug)]
+struct PaletteError {
+    line: usize,
+    message: String,
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+fn palette_error(line: usize, message: impl Into<String>) -> PaletteError {
+    PaletteError { line, message: message.into() }
+}
+
+// "R G B" with anything after the third number (a colour name) ignored
+fn parse_rgb_line(line_number: usize, line: &str) -> Result<Color, PaletteError> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 3 {
+        return Err(palette_error(line_number, format!("expected 'R G B', found '{}'", line.trim())));
+    }
+    let mut color = [0, 0, 0, 255];
+    for (channel, part) in color.iter_mut().zip(&parts[..3]) {
+        *channel = part
+            .parse()
+            .map_err(|_| palette_error(line_number, format!("'{}' is not a colour value from 0 to 255", part)))?;
+    }
+    Ok(color)
+}
+
+fn parse_gpl(text: &str) -> Result<Vec<Color>, PaletteError> {
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+    match lines.next() {
+        Some((_, header)) if header.trim() == "GIMP Palette" => {}
+        _ => return Err(palette_error(1, "missing 'GIMP Palette' header")),
+    }
+    let mut colors = Vec::new();
+    for (number, line) in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("Name:") || trimmed.starts_with("Columns:") {
+            continue;
+        }
+        colors.push(parse_rgb_line(number, line)?);
+    }
+    Ok(colors)
+}
+
+fn parse_jasc_pal(text: &str) -> Result<Vec<Color>, PaletteError> {
+    let lines: Vec<&str> = text.lines().map(|line| line.trim()).collect();
+    if lines.first() != Some(&"JASC-PAL") {
+        return Err(palette_error(1, "missing 'JASC-PAL' header"));
+    }
+    if lines.get(1) != Some(&"0100") {
+        return Err(palette_error(2, "unsupported JASC-PAL version (expected 0100)"));
+    }
+    let count: usize = lines
+        .get(2)
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| palette_error(3, "expected the number of colours"))?;
+    let entries: Vec<(usize, &str)> =
+        lines.iter().enumerate().skip(3).filter(|(_, line)| !line.is_empty()).map(|(i, line)| (i + 1, *line)).collect();
+    if entries.len() < count {
+        return Err(palette_error(lines.len(), format!("expected {} colours, found {}", count, entries.len())));
+    }
+    entries.into_iter().take(count).map(|(number, line)| parse_rgb_line(number, line)).collect()
+}
+
+// RGB, RGBA, RRGGBB or RRGGBBAA hex digits
+fn hex_color(digits: &str) -> Option<Color> {
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let (width, scale) = match digits.len() {
+        3 | 4 => (1, 17),
+        6 | 8 => (2, 1),
+        _ => return None,
+    };
+    let mut color = [0, 0, 0, 255];
+    for (at, channel) in color.iter_mut().enumerate().take(digits.len() / width) {
+        *channel = u8::from_str_radix(&digits[at * width..(at + 1) * width], 16).ok()? * scale;
+    }
+    Some(color)
+}
+
+fn parse_hex_palette(text: &str) -> Result<Vec<Color>, PaletteError> {
+    let mut colors = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let digits = line.trim().trim_start_matches('#');
+        if digits.is_empty() {
+            continue;
+        }
+        let color = hex_color(digits)
+            .ok_or_else(|| palette_error(i + 1, format!("expected RRGGBB or RRGGBBAA, found '{}'", line.trim())))?;
+        colors.push(color);
+    }
+    Ok(colors)
+}
+
+fn format_gpl(colors: &[Color], name: &str) -> String {
+    let mut out = format!("GIMP Palette\nName: {}\nColumns: {}\n#\n", name, PALETTE_COLUMNS);
+    for c in colors {
+        out.push_str(&format!("{:>3} {:>3} {:>3}\t#{:02x}{:02x}{:02x}\n", c[0], c[1], c[2], c[0], c[1], c[2]));
+    }
+    out
+}
+
+fn format_jasc_pal(colors: &[Color]) -> String {
+    let mut out = format!("JASC-PAL\r\n0100\r\n{}\r\n", colors.len());
+    for c in colors {
+        out.push_str(&format!("{} {} {}\r\n", c[0], c[1], c[2]));
+    }
+    out
+}
+
+fn format_hex_palette(colors: &[Color]) -> String {
+    let mut out = String::new();
+    for c in colors {
+        out.push_str(&format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2]));
+        if c[3] != 255 {
+            out.push_str(&format!("{:02x}", c[3]));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn palette_extension(path: &str) -> Result<String, String> {
+    let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "gpl" | "pal" | "hex" => Ok(extension),
+        _ => Err(format!("unknown palette format '{}' (use .gpl, .pal or .hex)", path)),
+    }
+}
+
+fn load_palette(path: &str) -> Result<Vec<Color>, Box<dyn std::error::Error>> {
+    let extension = palette_extension(path)?;
+    let text = std::fs::read_to_string(expand_path(path))?;
+    let colors = match extension.as_str() {
+        "gpl" => parse_gpl(&text)?,
+        "pal" => parse_jasc_pal(&text)?,
+        _ => parse_hex_palette(&text)?,
+    };
+    if colors.is_empty() {
+        return Err("the palette has no colours".into());
+    }
+    if colors.len() > PALETTE_MAX_COLORS {
+        return Err(format!("the palette has {} colours; palettes hold at most {}", colors.len(), PALETTE_MAX_COLORS).into());
+    }
+    Ok(colors)
+}
+
+fn save_palette(path: &str, colors: &[Color]) -> Result<(), Box<dyn std::error::Error>> {
+    let extension = palette_extension(path)?;
+    let expanded_path = expand_path(path);
+    let name = std::path::Path::new(&expanded_path).file_stem().and_then(|s| s.to_str()).unwrap_or("Raint");
+    let text = match extension.as_str() {
+        "gpl" => format_gpl(colors, name),
+        "pal" => format_jasc_pal(colors),
+        _ => format_hex_palette(colors),
+    };
+    std::fs::write(&expanded_path, text)?;
+    Ok(())
+}
+
+// The distinct colours of the flattened canvas in reading order, skipping fully
+// transparent pixels. Fails with the colour count when there are too many to be useful.
+fn canvas_palette(canvas: &Canvas) -> Result<Vec<Color>, usize> {
+    let mut seen = std::collections::HashSet::new();
+    let colors: Vec<Color> = canvas.pixels.iter().filter(|p| p[3] != 0 && seen.insert(**p)).copied().collect();
+    if colors.len() > PALETTE_MAX_COLORS {
+        return Err(colors.len());
+    }
+    Ok(colors)
+}
+
+// CSS named colours; where X11 differs (gray, green, maroon, purple) the CSS value wins
+const NAMED_COLORS: [(&str, u32); 148] = [
+    ("aliceblue", 0xf0f8ff), ("antiquewhite", 0xfaebd7), ("aqua", 0x00ffff), ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff), ("beige", 0xf5f5dc), ("bisque", 0xffe4c4), ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd), ("blue", 0x0000ff), ("blueviolet", 0x8a2be2), ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887), ("cadetblue", 0x5f9ea0), ("chartreuse", 0x7fff00), ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50), ("cornflowerblue", 0x6495ed), ("cornsilk", 0xfff8dc), ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff), ("darkblue", 0x00008b), ("darkcyan", 0x008b8b), ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9), ("darkgreen", 0x006400), ("darkgrey", 0xa9a9a9), ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b), ("darkolivegreen", 0x556b2f), ("darkorange", 0xff8c00), ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000), ("darksalmon", 0xe9967a), ("darkseagreen", 0x8fbc8f), ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f), ("darkslategrey", 0x2f4f4f), ("darkturquoise", 0x00ced1), ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493), ("deepskyblue", 0x00bfff), ("dimgray", 0x696969), ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff), ("firebrick", 0xb22222), ("floralwhite", 0xfffaf0), ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff), ("gainsboro", 0xdcdcdc), ("ghostwhite", 0xf8f8ff), ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520), ("gray", 0x808080), ("green", 0x008000), ("greenyellow", 0xadff2f),
+    ("grey", 0x808080), ("honeydew", 0xf0fff0), ("hotpink", 0xff69b4), ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082), ("ivory", 0xfffff0), ("khaki", 0xf0e68c), ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5), ("lawngreen", 0x7cfc00), ("lemonchiffon", 0xfffacd), ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080), ("lightcyan", 0xe0ffff), ("lightgoldenrodyellow", 0xfafad2), ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90), ("lightgrey", 0xd3d3d3), ("lightpink", 0xffb6c1), ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa), ("lightskyblue", 0x87cefa), ("lightslategray", 0x778899), ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de), ("lightyellow", 0xffffe0), ("lime", 0x00ff00), ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6), ("magenta", 0xff00ff), ("maroon", 0x800000), ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd), ("mediumorchid", 0xba55d3), ("mediumpurple", 0x9370db), ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee), ("mediumspringgreen", 0x00fa9a), ("mediumturquoise", 0x48d1cc), ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970), ("mintcream", 0xf5fffa), ("mistyrose", 0xffe4e1), ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead), ("navy", 0x000080), ("oldlace", 0xfdf5e6), ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23), ("orange", 0xffa500), ("orangered", 0xff4500), ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa), ("palegreen", 0x98fb98), ("paleturquoise", 0xafeeee), ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5), ("peachpuff", 0xffdab9), ("peru", 0xcd853f), ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd), ("powderblue", 0xb0e0e6), ("purple", 0x800080), ("rebeccapurple", 0x663399),
+    ("red", 0xff0000), ("rosybrown", 0xbc8f8f), ("royalblue", 0x4169e1), ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072), ("sandybrown", 0xf4a460), ("seagreen", 0x2e8b57), ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d), ("silver", 0xc0c0c0), ("skyblue", 0x87ceeb), ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090), ("slategrey", 0x708090), ("snow", 0xfffafa), ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4), ("tan", 0xd2b48c), ("teal", 0x008080), ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347), ("turquoise", 0x40e0d0), ("violet", 0xee82ee), ("wheat", 0xf5deb3),
+    ("white", 0xffffff), ("whitesmoke", 0xf5f5f5), ("yellow", 0xffff00), ("yellowgreen", 0x9acd32),
+];
+
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [u8; 3] {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    [r, g, b].map(|c| ((c + m) * 255.0).round() as u8)
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> [u8; 3] {
+    let value = lightness + saturation * lightness.min(1.0 - lightness);
+    let hsv_saturation = if value == 0.0 { 0.0 } else { 2.0 * (1.0 - lightness / value) };
+    hsv_to_rgb(hue, hsv_saturation, value)
+}
+
+// A number in `min..=max` with an optional unit suffix stripped first
+fn color_number(text: &str, suffix: &str, min: f32, max: f32, what: &str) -> Result<f32, String> {
+    text.strip_suffix(suffix)
+        .unwrap_or(text)
+        .parse::<f32>()
+        .ok()
+        .filter(|n| (min..=max).contains(n))
+        .ok_or_else(|| format!("{} must be a number from {} to {}, got '{}'", what, min, max, text))
+}
+
+// Alpha in a colour function is 0-1, or a percentage
+fn color_alpha(text: &str) -> Result<u8, String> {
+    let alpha = match text.strip_suffix('%') {
+        Some(percent) => color_number(percent, "", 0.0, 100.0, "alpha")? / 100.0,
+        None => color_number(text, "", 0.0, 1.0, "alpha")?,
+    };
+    Ok((alpha * 255.0).round() as u8)
+}
+
+// Input for the colour prompt: `R G B [A]`, `#RGB`, `#RRGGBB` (either with an alpha
+// digit pair), `rgb(r, g, b)`, `hsv(h, s, v)`, `hsl(h, s, l)` with hue in degrees and
+// the rest in percent, or a CSS colour name. Colour functions take an optional alpha.
+fn parse_color(input: &str) -> Result<Color, String> {
+    let text = input.trim().to_lowercase();
+
+    if let Some(digits) = text.strip_prefix('#') {
+        return hex_color(digits).ok_or_else(|| format!("'{}' is not a #RGB, #RRGGBB or #RRGGBBAA colour", input.trim()));
+    }
+
+    if let Some((function, rest)) = text.split_once('(') {
+        let function = function.trim();
+        let args: Vec<&str> = rest
+            .strip_suffix(')')
+            .ok_or_else(|| format!("{}(...) is missing its closing ')'", function))?
+            .split([',', ' ', '/'])
+            .filter(|arg| !arg.is_empty())
+            .collect();
+        if args.len() != 3 && args.len() != 4 {
+            return Err(format!("{}() needs 3 values and an optional alpha, got {}", function, args.len()));
+        }
+        let alpha = args.get(3).map_or(Ok(255), |a| color_alpha(a))?;
+        let [r, g, b] = match function {
+            "rgb" | "rgba" => {
+                let mut rgb = [0; 3];
+                for (channel, arg) in rgb.iter_mut().zip(&args) {
+                    *channel = color_number(arg, "", 0.0, 255.0, "rgb() values")? as u8;
+                }
+                rgb
+            }
+            "hsv" | "hsb" | "hsl" | "hsla" => {
+                let hue = args[0]
+                    .trim_end_matches("deg")
+                    .trim_end_matches('°')
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|hue| hue.is_finite())
+                    .ok_or_else(|| format!("hue must be a number of degrees, got '{}'", args[0]))?;
+                let second = color_number(args[1], "%", 0.0, 100.0, "saturation")? / 100.0;
+                let third = color_number(args[2], "%", 0.0, 100.0, if function.starts_with("hsl") { "lightness" } else { "value" })?
+                    / 100.0;
+                if function.starts_with("hsl") {
+                    hsl_to_rgb(hue, second, third)
+                } else {
+                    hsv_to_rgb(hue, second, third)
+                }
+            }
+            _ => return Err(format!("unknown colour function '{}' (use rgb, hsv or hsl)", function)),
+        };
+        return Ok([r, g, b, alpha]);
+    }
+
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        let parts: Vec<&str> = text.split([',', ' ']).filter(|part| !part.is_empty()).collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            return Err(format!("expected R G B [A], got {} values", parts.len()));
+        }
+        let mut color = [0, 0, 0, 255];
+        for (channel, part) in color.iter_mut().zip(&parts) {
+            *channel = part.parse().map_err(|_| format!("'{}' is not a value from 0 to 255", part))?;
+        }
+        return Ok(color);
+    }
+
+    let name: String = text.chars().filter(|c| !matches!(c, ' ' | '-' | '_')).collect();
+    if name == "transparent" {
+        return Ok(TRANSPARENT);
+    }
+    NAMED_COLORS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, rgb)| [(rgb >> 16) as u8, (rgb >> 8) as u8, *rgb as u8, 255])
+        .ok_or_else(|| format!("unknown colour '{}'", input.trim()))
+}
+
+fn rgb_to_hsv(color: Color) -> (f32, f32, f32) {
+    let [r, g, b] = [color[0], color[1], color[2]].map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    let hue = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / chroma + 2.0)
+    } else {
+        60.0 * ((r - g) / chroma + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+    (hue, saturation, max)
+}
+
+const RECENT_COLORS: usize = 16;
+
+// Most recent first, without repeats
+fn remember_color(recent: &mut VecDeque<Color>, color: Color) {
+    recent.retain(|c| *c != color);
+    recent.push_front(color);
+    recent.truncate(RECENT_COLORS);
+}
+
+fn color_cell(color: Color, x: usize, y: usize, text: &str) -> Span<'static> {
+    let [r, g, b] = display_color(color, x, y);
+    let ink = if r as u32 * 3 + g as u32 * 6 + b as u32 > 1280 { RColor::Black } else { RColor::White };
+    Span::styled(text.to_string(), Style::default().bg(RColor::Rgb(r, g, b)).fg(ink))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PickerPart {
+    Square,
+    Hue,
+    Alpha,
+}
+
+// Full-screen HSV picker: a saturation/value square, a hue bar and an alpha slider,
+// with the colour it was opened with kept alongside for comparison.
+struct ColorPicker {
+    hue: f32,
+    saturation: f32,
+    value: f32,
+    alpha: u8,
+    before: Color,
+    message: Option<String>,
+    // Screen rectangles from the last layout, for mouse hits
+    square: Rect,
+    hue_bar: Rect,
+    alpha_bar: Rect,
+    before_swatch: Rect,
+    recent_row: Rect,
+    dragging: Option<PickerPart>,
+}
+
+impl ColorPicker {
+    fn new(color: Color) -> Self {
+        let mut picker = ColorPicker {
+            hue: 0.0,
+            saturation: 0.0,
+            value: 0.0,
+            alpha: 255,
+            before: color,
+            message: None,
+            square: Rect::default(),
+            hue_bar: Rect::default(),
+            alpha_bar: Rect::default(),
+            before_swatch: Rect::default(),
+            recent_row: Rect::default(),
+            dragging: None,
+        };
+        picker.set_color(color);
+        picker
+    }
+
+    // Greys and black have no hue of their own, so the bar stays where it was
+    fn set_color(&mut self, color: Color) {
+        let (hue, saturation, value) = rgb_to_hsv(color);
+        if saturation > 0.0 {
+            self.hue = hue;
+        }
+        if value > 0.0 {
+            self.saturation = saturation;
+        }
+        self.value = value;
+        self.alpha = color[3];
+    }
+
+    fn color(&self) -> Color {
+        let [r, g, b] = hsv_to_rgb(self.hue, self.saturation, self.value);
+        [r, g, b, self.alpha]
+    }
+
+    fn layout(&mut self, area: Rect) {
+        let width = area.width.saturating_sub(32).clamp(8, 48);
+        let height = area.height.saturating_sub(10).clamp(4, 20);
+        self.square = Rect::new(area.x + 2, area.y + 2, width, height);
+        self.hue_bar = Rect::new(self.square.right() + 2, self.square.y, 3, height);
+        self.before_swatch = Rect::new(self.hue_bar.right() + 3, self.square.y + 1, 8, 3);
+        self.alpha_bar = Rect::new(self.square.x, self.square.bottom() + 1, width + 5, 1);
+        self.recent_row = Rect::new(self.square.x, self.alpha_bar.bottom() + 2, RECENT_COLORS as u16 * SWATCH_WIDTH, 1);
+    }
+
+    fn widgets(&self, area: Rect, recent: &VecDeque<Color>) -> Vec<(Paragraph<'static>, Rect)> {
+        let mut widgets = Vec::new();
+        let frame = Block::default().borders(Borders::ALL).title(" Colour picker ");
+        let inner = frame.inner(area);
+
+        let (w, h) = (self.square.width as usize, self.square.height as usize);
+        let (marker_x, marker_y) = self.square_cell();
+        let square: Vec<Line> = (0..h)
+            .map(|y| {
+                let value = 1.0 - y as f32 / (h - 1) as f32;
+                let cells = (0..w).map(|x| {
+                    let [r, g, b] = hsv_to_rgb(self.hue, x as f32 / (w - 1) as f32, value);
+                    color_cell([r, g, b, 255], x, y, if (x, y) == (marker_x, marker_y) { "+" } else { " " })
+                });
+                Line::from(cells.collect::<Vec<_>>())
+            })
+            .collect();
+        widgets.push((Paragraph::new(square), self.square));
+
+        let marker_row = self.hue_row();
+        let hue_bar: Vec<Line> = (0..h)
+            .map(|y| {
+                let [r, g, b] = hsv_to_rgb(y as f32 / (h - 1) as f32 * 360.0, 1.0, 1.0);
+                Line::from(color_cell([r, g, b, 255], 0, 0, if y == marker_row { "[ ]" } else { "   " }))
+            })
+            .collect();
+        widgets.push((Paragraph::new(hue_bar), self.hue_bar));
+
+        let alpha_cells = self.alpha_bar.width as usize;
+        let marker = (self.alpha as usize * (alpha_cells - 1) + 127) / 255;
+        let color = self.color();
+        let alpha_bar: Vec<Span> = (0..alpha_cells)
+            .map(|x| {
+                let alpha = (x * 255 / (alpha_cells - 1)) as u8;
+                color_cell([color[0], color[1], color[2], alpha], x, 0, if x == marker { "|" } else { " " })
+            })
+            .collect();
+        widgets.push((Paragraph::new(Line::from(alpha_bar)), self.alpha_bar));
+        let alpha_label = format!("Alpha {} ({}%)", self.alpha, self.alpha as usize * 100 / 255);
+        widgets.push((Paragraph::new(alpha_label), Rect::new(self.square.x, self.alpha_bar.y - 1, self.alpha_bar.width, 1)));
+
+        let recent_cells: Vec<Span> = recent.iter().map(|c| color_cell(*c, 0, 0, "   ")).collect();
+        widgets.push((Paragraph::new(Line::from(recent_cells)), self.recent_row));
+        widgets.push((Paragraph::new("Recent (1-9)"), Rect::new(self.square.x, self.recent_row.y - 1, 20, 1)));
+
+        let swatch = |c: Color| -> Vec<Line<'static>> {
+            (0..3).map(|y| Line::from((0..8).map(|x| color_cell(c, x, y, " ")).collect::<Vec<_>>())).collect()
+        };
+        let side_x = self.before_swatch.x;
+        let after_swatch = Rect::new(side_x, self.before_swatch.bottom() + 2, 8, 3);
+        widgets.push((Paragraph::new("Before"), Rect::new(side_x, self.square.y, 8, 1)));
+        widgets.push((Paragraph::new(swatch(self.before)), self.before_swatch));
+        widgets.push((Paragraph::new("After"), Rect::new(side_x, after_swatch.y - 1, 8, 1)));
+        widgets.push((Paragraph::new(swatch(color)), after_swatch));
+
+        let details = vec![
+            Line::from(format!("#{:02x}{:02x}{:02x}{:02x}", color[0], color[1], color[2], color[3])),
+            Line::from(format!("RGBA {} {} {} {}", color[0], color[1], color[2], color[3])),
+            Line::from(format!(
+                "HSV {:.0}° {:.0}% {:.0}%",
+                self.hue,
+                self.saturation * 100.0,
+                self.value * 100.0
+            )),
+        ];
+        let details_area = Rect::new(side_x, after_swatch.bottom() + 1, 24, 3);
+        widgets.push((Paragraph::new(details), details_area));
+
+        let help = "Arrows: saturation/value (Shift x5) | [ ]: hue (Shift { } x6) | - +: alpha | 1-9: recent | C: type | R: revert | Enter: pick | Esc: cancel";
+        let help_line = match &self.message {
+            Some(message) => Line::from(Span::styled(message.clone(), Style::default().fg(RColor::Red).add_modifier(Modifier::BOLD))),
+            None => Line::from(help),
+        };
+        let help_area = Rect::new(area.x + 2, area.bottom().saturating_sub(2), area.width.saturating_sub(4), 1);
+        widgets.push((Paragraph::new(help_line), help_area));
+
+        // Anything that does not fit on a small terminal is cut off at the frame
+        let mut clipped = vec![(Paragraph::new("").block(frame), area)];
+        clipped.extend(widgets.into_iter().map(|(widget, rect)| (widget, rect.intersection(inner))));
+        clipped
+    }
+
+    fn square_cell(&self) -> (usize, usize) {
+        let (w, h) = (self.square.width as f32 - 1.0, self.square.height as f32 - 1.0);
+        ((self.saturation * w).round() as usize, ((1.0 - self.value) * h).round() as usize)
+    }
+
+    fn hue_row(&self) -> usize {
+        (self.hue / 360.0 * (self.square.height as f32 - 1.0)).round() as usize
+    }
+
+    fn pick_recent(&mut self, recent: &VecDeque<Color>, index: usize) {
+        if let Some(color) = recent.get(index) {
+            self.set_color(*color);
+        }
+    }
+
+    fn handle_key(&mut self, key: &KeyEvent, recent: &VecDeque<Color>) {
+        let cells = if key.modifiers.contains(KeyModifiers::SHIFT) { 5.0 } else { 1.0 };
+        let saturation_step = cells / (self.square.width as f32 - 1.0);
+        let value_step = cells / (self.square.height as f32 - 1.0);
+        match key.code {
+            KeyCode::Left | KeyCode::Char('h') => self.saturation = (self.saturation - saturation_step).max(0.0),
+            KeyCode::Right | KeyCode::Char('l') => self.saturation = (self.saturation + saturation_step).min(1.0),
+            KeyCode::Up | KeyCode::Char('k') => self.value = (self.value + value_step).min(1.0),
+            KeyCode::Down | KeyCode::Char('j') => self.value = (self.value - value_step).max(0.0),
+            KeyCode::Char('[') => self.hue = (self.hue - 5.0).rem_euclid(360.0),
+            KeyCode::Char(']') => self.hue = (self.hue + 5.0).rem_euclid(360.0),
+            KeyCode::Char('{') => self.hue = (self.hue - 30.0).rem_euclid(360.0),
+            KeyCode::Char('}') => self.hue = (self.hue + 30.0).rem_euclid(360.0),
+            KeyCode::Char('-') | KeyCode::Char('_') => self.alpha = self.alpha.saturating_sub(15),
+            KeyCode::Char('+') | KeyCode::Char('=') => self.alpha = self.alpha.saturating_add(15),
+            KeyCode::Char('r') | KeyCode::Char('R') => self.set_color(self.before),
+            KeyCode::Char(digit @ '1'..='9') => self.pick_recent(recent, digit as usize - '1' as usize),
+            _ => {}
+        }
+    }
+
+    // A drag keeps adjusting whatever it started on, even once it leaves that control
+    fn handle_mouse(&mut self, mouse_event: &MouseEvent, recent: &VecDeque<Color>) {
+        use crossterm::event::{MouseButton, MouseEventKind};
+
+        let (column, row) = (mouse_event.column, mouse_event.row);
+        let inside = |r: Rect| column >= r.x && column < r.right() && row >= r.y && row < r.bottom();
+        match mouse_event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                self.dragging = if inside(self.square) {
+                    Some(PickerPart::Square)
+                } else if inside(self.hue_bar) {
+                    Some(PickerPart::Hue)
+                } else if inside(self.alpha_bar) {
+                    Some(PickerPart::Alpha)
+                } else {
+                    if inside(self.before_swatch) {
+                        self.set_color(self.before);
+                    } else if inside(self.recent_row) {
+                        self.pick_recent(recent, ((column - self.recent_row.x) / SWATCH_WIDTH) as usize);
+                    }
+                    None
+                };
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {}
+            MouseEventKind::Up(_) => {
+                self.dragging = None;
+                return;
+            }
+            _ => return,
+        }
+
+        let fraction = |offset: u16, start: u16, len: u16| (offset.saturating_sub(start) as f32 / (len as f32 - 1.0)).min(1.0);
+        match self.dragging {
+            Some(PickerPart::Square) => {
+                self.saturation = fraction(column, self.square.x, self.square.width);
+                self.value = 1.0 - fraction(row, self.square.y, self.square.height);
+            }
+            Some(PickerPart::Hue) => self.hue = fraction(row, self.hue_bar.y, self.hue_bar.height) * 360.0,
+            Some(PickerPart::Alpha) => {
+                self.alpha = (fraction(column, self.alpha_bar.x, self.alpha_bar.width) * 255.0).round() as u8
+            }
+            None => {}
+        }
+    }
+}
+
+// A selection is a per-pixel mask over the canvas; only pixels inside it are
+// copied, cut or moved.
+#[derive(Clone)]
+struct Selection {
+    width: usize,
+    height: usize,
+    mask: Vec<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SelectionOp {
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+impl SelectionOp {
+    fn label(self) -> &'static str {
+        match self {
+            SelectionOp::Replace => "replace",
+            SelectionOp::Add => "add",
+            SelectionOp::Subtract => "subtract",
+            SelectionOp::Intersect => "intersect",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SelectTool {
+    Rect,
+    Wand,
+    Lasso,
+}
+
+impl SelectTool {
+    fn label(self) -> &'static str {
+        match self {
+            SelectTool::Rect => "rect",
+            SelectTool::Wand => "wand",
+            SelectTool::Lasso => "lasso",
+        }
+    }
+}
+
+impl Selection {
+    fn empty(width: usize, height: usize) -> Self {
+        Selection { width, height, mask: vec![false; width * height] }
+    }
+
+    fn rect(width: usize, height: usize, x0: i32, y0: i32, x1: i32, y1: i32) -> Self {
+        let mut mask = vec![false; width * height];
+        let x_min = x0.min(x1).max(0) as usize;
+        let y_min = y0.min(y1).max(0) as usize;
+        let x_max = (x0.max(x1) + 1).clamp(0, width as i32) as usize;
+        let y_max = (y0.max(y1) + 1).clamp(0, height as i32) as usize;
+        for y in y_min..y_max {
+            for x in x_min..x_max {
+                mask[y * width + x] = true;
+            }
+        }
+        Selection { width, height, mask }
+    }
+
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+            && self.mask[y as usize * self.width + x as usize]
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.mask.iter().any(|&m| m)
+    }
+
+    // Smallest rectangle covering the selection as (x, y, width, height)
+    fn bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        let mut min = (usize::MAX, usize::MAX);
+        let mut max = (0, 0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.mask[y * self.width + x] {
+                    min = (min.0.min(x), min.1.min(y));
+                    max = (max.0.max(x), max.1.max(y));
+                }
+            }
+        }
+        if min.0 == usize::MAX {
+            return None;
+        }
+        Some((min.0, min.1, max.0 - min.0 + 1, max.1 - min.1 + 1))
+    }
+
+    fn is_edge(&self, x: usize, y: usize) -> bool {
+        let (x, y) = (x as i32, y as i32);
+        self.contains(x, y)
+            && (!self.contains(x - 1, y) || !self.contains(x + 1, y) || !self.contains(x, y - 1) || !self.contains(x, y + 1))
+    }
+
+    // Applies `op` with `other` on top of an optional existing selection. An
+    // empty result means nothing is selected.
+    fn combine(base: Option<&Selection>, other: Selection, op: SelectionOp) -> Option<Selection> {
+        let result = match (base, op) {
+            (Some(base), SelectionOp::Add) => Selection {
+                mask: base.mask.iter().zip(&other.mask).map(|(&a, &b)| a || b).collect(),
+                ..other
+            },
+            (Some(base), SelectionOp::Subtract) => Selection {
+                mask: base.mask.iter().zip(&other.mask).map(|(&a, &b)| a && !b).collect(),
+                ..other
+            },
+            (Some(base), SelectionOp::Intersect) => Selection {
+                mask: base.mask.iter().zip(&other.mask).map(|(&a, &b)| a && b).collect(),
+                ..other
+            },
+            (None, SelectionOp::Subtract | SelectionOp::Intersect) => return None,
+            _ => other,
+        };
+        if result.is_empty() { None } else { Some(result) }
+    }
+
+    fn translated(&self, dx: i32, dy: i32) -> Self {
+        let mut mask = vec![false; self.width * self.height];
+        for y in 0..self.height as i32 {
+            for x in 0..self.width as i32 {
+                if self.contains(x - dx, y - dy) {
+                    mask[y as usize * self.width + x as usize] = true;
+                }
+            }
+        }
+        Selection { width: self.width, height: self.height, mask }
+    }
+}
+
+impl Canvas {
+    fn copy_region(&self, selection: &Selection) -> Option<Canvas> {
+        let (bx, by, bw, bh) = selection.bounds()?;
+        let mut clip = Canvas::new_transparent(bw, bh);
+        for y in 0..bh {
+            for x in 0..bw {
+                if selection.contains((bx + x) as i32, (by + y) as i32) {
+                    clip.set_pixel(x, y, self.get_pixel(bx + x, by + y));
+                }
+            }
+        }
+        Some(clip)
+    }
+
+    // Puts back every pixel outside the selection from `before`, so tools only
+    // change what is selected
+    fn clip_to_selection(&mut self, before: &Canvas, selection: Option<&Selection>) {
+        let Some(sel) = selection else {
+            return;
+        };
+        if before.pixels.len() != self.pixels.len() || sel.mask.len() != self.pixels.len() {
+            return;
+        }
+        for ((pixel, &old), &inside) in self.pixels.iter_mut().zip(&before.pixels).zip(&sel.mask) {
+            if !inside {
+                *pixel = old;
+            }
+        }
+    }
+
+    fn clear_region(&mut self, selection: &Selection) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if selection.contains(x as i32, y as i32) {
+                    self.set_pixel(x, y, TRANSPARENT);
+                }
+            }
+        }
+    }
+
+    fn paste(&mut self, clip: &Canvas, x: i32, y: i32) {
+        for cy in 0..clip.height {
+            for cx in 0..clip.width {
+                let px = x + cx as i32;
+                let py = y + cy as i32;
+                if px >= 0 && py >= 0 && (px as usize) < self.width && (py as usize) < self.height {
+                    let below = self.get_pixel(px as usize, py as usize);
+                    self.set_pixel(px as usize, py as usize, blend_over(below, clip.get_pixel(cx, cy), 100));
+                }
+            }
+        }
+    }
+}
+
+// Pixels lifted off a layer while they are dragged around. `base` is the stack
+// with the hole already cut, `origin` is where the pixels were lifted from.
+struct FloatingPixels {
+    pixels: Canvas,
+    base: LayerStack,
+    origin: (i32, i32),
+    x: i32,
+    y: i32,
+    grab: (i32, i32),
+}
+
+// Marching ants: the outline pattern shifts every few frames
+fn ants_phase() -> usize {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    (millis / 200 % 4) as usize
+}
+
+fn ant_color(x: usize, y: usize, phase: usize) -> [u8; 3] {
+    if (x + y + phase) % 4 < 2 { [0, 0, 0] } else { [255, 255, 255] }
+}
+
+// Transparent areas are shown over a light/dark checkerboard, one square per pixel
+fn checker_color(x: usize, y: usize) -> [u8; 3] {
+    if (x + y).is_multiple_of(2) { [204, 204, 204] } else { [153, 153, 153] }
+}
+
+fn display_color(pixel: Color, x: usize, y: usize) -> [u8; 3] {
+    let bg = checker_color(x, y);
+    let a = pixel[3] as u16;
+    let mix = |c: u8, b: u8| ((c as u16 * a + b as u16 * (255 - a)) / 255) as u8;
+    [mix(pixel[0], bg[0]), mix(pixel[1], bg[1]), mix(pixel[2], bg[2])]
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Zoom {
+    HalfBlock,
+    One,
+    Two,
+    Four,
+}
+
+impl Zoom {
+    // Terminal cells used per canvas pixel: (columns, rows). Half-block mode packs
+    // two pixels into one cell with '▀', so it is the only level below one row.
+    fn cells_per_pixel(self) -> (usize, usize) {
+        match self {
+            Zoom::HalfBlock | Zoom::One => (1, 1),
+            Zoom::Two => (2, 1),
+            Zoom::Four => (4, 2),
+        }
+    }
+
+    fn zoom_in(self) -> Zoom {
+        match self {
+            Zoom::HalfBlock => Zoom::One,
+            Zoom::One => Zoom::Two,
+            Zoom::Two | Zoom::Four => Zoom::Four,
+        }
+    }
+
+    fn zoom_out(self) -> Zoom {
+        match self {
+            Zoom::Four => Zoom::Two,
+            Zoom::Two => Zoom::One,
+            Zoom::One | Zoom::HalfBlock => Zoom::HalfBlock,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Zoom::HalfBlock => "half-block, mouse view-only",
+            Zoom::One => "1 cell",
+            Zoom::Two => "2 cells",
+            Zoom::Four => "4 cells",
+        }
+    }
+}
+
+// Keyboard stand-in for the mouse. While the pen is down, cursor moves act as drags.
+struct KeyCursor {
+    x: i32,
+    y: i32,
+    pen_down: bool,
+}
+
+// Maps between terminal cells in `area` and canvas pixels, starting at the pan offset
+struct Viewport {
+    offset_x: usize,
+    offset_y: usize,
+    zoom: Zoom,
+    area: Rect,
+    drag_anchor: Option<(u16, u16)>,
+    cursor: Option<KeyCursor>,
+    // Mouse events synthesised from cursor keys, with the canvas pixel they target
+    pending: VecDeque<((i32, i32), Event)>,
+    pointer: Option<(i32, i32)>,
+    // Pixels that blink like the keyboard cursor, e.g. curve handles
+    markers: HashSet<(usize, usize)>,
+    // Keys the current tool binds itself, which the keyboard cursor leaves alone
+    tool_keys: &'static [KeyCode],
+}
+
+impl Viewport {
+    fn new() -> Self {
+        Viewport {
+            offset_x: 0,
+            offset_y: 0,
+            zoom: Zoom::Two,
+            area: Rect::default(),
+            drag_anchor: None,
+            cursor: None,
+            pending: VecDeque::new(),
+            pointer: None,
+            markers: HashSet::new(),
+            tool_keys: &[],
+        }
+    }
+
+    fn visible_pixels(&self) -> (usize, usize) {
+        let (cols, rows) = self.zoom.cells_per_pixel();
+        let w = self.area.width as usize / cols;
+        let h = if self.zoom == Zoom::HalfBlock {
+            self.area.height as usize * 2
+        } else {
+            self.area.height as usize / rows
+        };
+        (w.max(1), h.max(1))
+    }
+
+    fn clamp_offset(&mut self, canvas_width: usize, canvas_height: usize) {
+        let (w, h) = self.visible_pixels();
+        self.offset_x = self.offset_x.min(canvas_width.saturating_sub(w));
+        self.offset_y = self.offset_y.min(canvas_height.saturating_sub(h));
+    }
+
+    fn pan(&mut self, dx: i32, dy: i32, canvas_width: usize, canvas_height: usize) {
+        self.offset_x = (self.offset_x as i32 + dx).max(0) as usize;
+        self.offset_y = (self.offset_y as i32 + dy).max(0) as usize;
+        self.clamp_offset(canvas_width, canvas_height);
+    }
+
+    fn screen_to_canvas(&self, column: u16, row: u16) -> Option<(i32, i32)> {
+        if column < self.area.x
+            || row < self.area.y
+            || column >= self.area.x + self.area.width
+            || row >= self.area.y + self.area.height
+        {
+            return None;
+        }
+        // A half-block cell shows two pixel rows and a click can't say which one it
+        // meant, so that zoom is view-only for the mouse; the keyboard cursor still edits
+        if self.zoom == Zoom::HalfBlock {
+            return None;
+        }
+        let (cols, rows) = self.zoom.cells_per_pixel();
+        let col = (column - self.area.x) as usize;
+        let row = (row - self.area.y) as usize;
+        Some(((self.offset_x + col / cols) as i32, (self.offset_y + row / rows) as i32))
+    }
+
+    // Canvas position of a mouse event; synthetic cursor events carry their own.
+    fn mouse_to_canvas(&self, mouse_event: &MouseEvent) -> Option<(i32, i32)> {
+        self.pointer.or_else(|| self.screen_to_canvas(mouse_event.column, mouse_event.row))
+    }
+
+    fn reveal(&mut self, x: i32, y: i32, canvas_width: usize, canvas_height: usize) {
+        let (w, h) = self.visible_pixels();
+        let (x, y) = (x.max(0) as usize, y.max(0) as usize);
+        if x < self.offset_x {
+            self.offset_x = x;
+        } else if x >= self.offset_x + w {
+            self.offset_x = x + 1 - w;
+        }
+        if y < self.offset_y {
+            self.offset_y = y;
+        } else if y >= self.offset_y + h {
+            self.offset_y = y + 1 - h;
+        }
+        self.clamp_offset(canvas_width, canvas_height);
+    }
+
+    fn toggle_cursor(&mut self, canvas_width: usize, canvas_height: usize) {
+        if self.cursor.take().is_some() {
+            return;
+        }
+        let (w, h) = self.visible_pixels();
+        let x = (self.offset_x + w.min(canvas_width) / 2).min(canvas_width - 1) as i32;
+        let y = (self.offset_y + h.min(canvas_height) / 2).min(canvas_height - 1) as i32;
+        self.cursor = Some(KeyCursor { x, y, pen_down: false });
+    }
+
+    fn lift_pen(&mut self) {
+        if let Some(cursor) = &mut self.cursor {
+            cursor.pen_down = false;
+        }
+    }
+
+    fn cursor_label(&self) -> String {
+        match &self.cursor {
+            Some(cursor) if cursor.pen_down => format!(" | Cursor {},{} (pen down)", cursor.x, cursor.y),
+            Some(cursor) => format!(" | Cursor {},{}", cursor.x, cursor.y),
+            None => String::new(),
+        }
+    }
+
+    fn poll_event(&self, timeout: Duration) -> io::Result<bool> {
+        if !self.pending.is_empty() {
+            return Ok(true);
+        }
+        event::poll(timeout)
+    }
+
+    fn read_event(&mut self) -> io::Result<Event> {
+        if let Some((pos, ev)) = self.pending.pop_front() {
+            self.pointer = Some(pos);
+            return Ok(ev);
+        }
+        self.pointer = None;
+        event::read()
+    }
+
+    // hjkl/arrows move the cursor (Shift+arrow by 8), Space clicks, Enter toggles the pen,
+    // except for keys in `tool_keys`. Each becomes a mouse event at the cursor, queued for
+    // `read_event`.
+    fn handle_cursor_key(&mut self, key: &KeyEvent, canvas_width: usize, canvas_height: usize) -> bool {
+        use crossterm::event::{MouseButton, MouseEventKind};
+
+        let Some(cursor) = &mut self.cursor else {
+            return false;
+        };
+        if self.tool_keys.contains(&key.code) {
+            return false;
+        }
+        let step = if key.modifiers.contains(KeyModifiers::SHIFT) { 8 } else { 1 };
+        let kinds = match key.code {
+            KeyCode::Char(' ') => vec![MouseEventKind::Down(MouseButton::Left), MouseEventKind::Up(MouseButton::Left)],
+            KeyCode::Enter => {
+                cursor.pen_down = !cursor.pen_down;
+                if cursor.pen_down {
+                    vec![MouseEventKind::Down(MouseButton::Left)]
+                } else {
+                    vec![MouseEventKind::Up(MouseButton::Left)]
+                }
+            }
+            _ => {
+                let (dx, dy) = match key.code {
+                    KeyCode::Left | KeyCode::Char('h') => (-step, 0),
+                    KeyCode::Right | KeyCode::Char('l') => (step, 0),
+                    KeyCode::Up | KeyCode::Char('k') => (0, -step),
+                    KeyCode::Down | KeyCode::Char('j') => (0, step),
+                    _ => return false,
+                };
+                cursor.x = (cursor.x + dx).clamp(0, canvas_width as i32 - 1);
+                cursor.y = (cursor.y + dy).clamp(0, canvas_height as i32 - 1);
+                if cursor.pen_down {
+                    vec![MouseEventKind::Drag(MouseButton::Left)]
+                } else {
+                    vec![MouseEventKind::Moved]
+                }
+            }
+        };
+
+        let pos = (cursor.x, cursor.y);
+        self.reveal(pos.0, pos.1, canvas_width, canvas_height);
+        for kind in kinds {
+            let mouse_event = MouseEvent { kind, column: 0, row: 0, modifiers: KeyModifiers::NONE };
+            self.pending.push_back((pos, Event::Mouse(mouse_event)));
+        }
+        true
+    }
+
+    fn render(&self, layers: &LayerStack, selection: Option<&Selection>) -> Vec<Line<'static>> {
+        let (vis_w, vis_h) = self.visible_pixels();
+        let x_end = (self.offset_x + vis_w).min(layers.width);
+        let y_end = (self.offset_y + vis_h).min(layers.height);
+        let (cols, rows) = self.zoom.cells_per_pixel();
+        let cell = "█".repeat(cols);
+        let mut lines = Vec::new();
+
+        let phase = ants_phase();
+        let cursor = self.cursor.as_ref().map(|c| (c.x as usize, c.y as usize));
+        let pixel_color = |x: usize, y: usize| -> [u8; 3] {
+            if cursor == Some((x, y)) || self.markers.contains(&(x, y)) {
+                return if phase < 2 { [0, 0, 0] } else { [255, 255, 255] };
+            }
+            match selection {
+                Some(sel) if sel.is_edge(x, y) => ant_color(x, y, phase),
+                _ => display_color(layers.composite_pixel(x, y), x, y),
+            }
+        };
+
+        if self.zoom == Zoom::HalfBlock {
+            for top_y in (self.offset_y..y_end).step_by(2) {
+                let bottom_y = top_y + 1;
+                let mut spans = Vec::new();
+                for x in self.offset_x..x_end {
+                    let top = pixel_color(x, top_y);
+                    let bottom = if bottom_y < layers.height {
+                        pixel_color(x, bottom_y)
+                    } else {
+                        [0, 0, 0]
+                    };
+                    let mut style = Style::default().fg(RColor::Rgb(top[0], top[1], top[2]));
+                    if bottom_y < layers.height {
+                        style = style.bg(RColor::Rgb(bottom[0], bottom[1], bottom[2]));
+                    }
+                    spans.push(Span::styled("▀", style));
+                }
+                lines.push(Line::from(spans));
+            }
+            return lines;
+        }
+
+        for y in self.offset_y..y_end {
+            let mut spans = Vec::new();
+            for x in self.offset_x..x_end {
+                let color = pixel_color(x, y);
+                let fg = RColor::Rgb(color[0], color[1], color[2]);
+                spans.push(Span::styled(cell.clone(), Style::default().fg(fg)));
+            }
+            let line = Line::from(spans);
+            for _ in 0..rows {
+                lines.push(line.clone());
+            }
+        }
+        lines
+    }
+
+    // Shared pan/zoom handling for every screen that shows the canvas. Returns true
+    // when the event was consumed.
+    fn handle_event(&mut self, event: &Event, canvas_width: usize, canvas_height: usize) -> bool {
+        use crossterm::event::{MouseButton, MouseEventKind};
+
+        let step = 4;
+        match event {
+            Event::Key(key) if self.handle_cursor_key(key, canvas_width, canvas_height) => true,
+            Event::Key(KeyEvent { code, .. }) => {
+                match code {
+                    KeyCode::Left => self.pan(-step, 0, canvas_width, canvas_height),
+                    KeyCode::Right => self.pan(step, 0, canvas_width, canvas_height),
+                    KeyCode::Up => self.pan(0, -step, canvas_width, canvas_height),
+                    KeyCode::Down => self.pan(0, step, canvas_width, canvas_height),
+                    KeyCode::Char('+') | KeyCode::Char('=') => self.zoom = self.zoom.zoom_in(),
+                    KeyCode::Char('-') | KeyCode::Char('_') => self.zoom = self.zoom.zoom_out(),
+                    _ => return false,
+                }
+                self.clamp_offset(canvas_width, canvas_height);
+                true
+            }
+            Event::Mouse(mouse_event) => {
+                let horizontal = mouse_event.modifiers.contains(KeyModifiers::SHIFT);
+                match mouse_event.kind {
+                    MouseEventKind::ScrollUp if horizontal => self.pan(-step, 0, canvas_width, canvas_height),
+                    MouseEventKind::ScrollDown if horizontal => self.pan(step, 0, canvas_width, canvas_height),
+                    MouseEventKind::ScrollUp => self.pan(0, -step, canvas_width, canvas_height),
+                    MouseEventKind::ScrollDown => self.pan(0, step, canvas_width, canvas_height),
+                    MouseEventKind::Down(MouseButton::Middle) => {
+                        self.drag_anchor = Some((mouse_event.column, mouse_event.row));
+                    }
+                    MouseEventKind::Drag(MouseButton::Middle) => {
+                        if let Some((ax, ay)) = self.drag_anchor {
+                            let (cols, rows) = self.zoom.cells_per_pixel();
+                            let dx = (ax as i32 - mouse_event.column as i32) / cols as i32;
+                            let dy_cells = ay as i32 - mouse_event.row as i32;
+                            let dy = if self.zoom == Zoom::HalfBlock { dy_cells * 2 } else { dy_cells / rows as i32 };
+                            if dx != 0 || dy != 0 {
+                                self.pan(dx, dy, canvas_width, canvas_height);
+                                self.drag_anchor = Some((mouse_event.column, mouse_event.row));
+                            }
+                        }
+                    }
+                    MouseEventKind::Up(MouseButton::Middle) => self.drag_anchor = None,
+                    _ => return false,
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn clamp(val: usize, min: usize, max: usize) -> usize {
+    if val < min { min } else if val > max { max } else { val }
+}
+
+fn draw_line_with_brush(canvas: &mut Canvas, x0: i32, y0: i32, x1: i32, y1: i32, thickness: usize, color: Color) {
+    let dx = (x1 - x0).abs();
+    let dy = (y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+
+    let mut err = dx - dy;
+    let mut x = x0;
+    let mut y = y0;
+
+    loop {
+        if x >= 0 && x < canvas.width as i32 && y >= 0 && y < canvas.height as i32 {
+            draw_brush_stroke(canvas, x as usize, y as usize, thickness, color);
+        }
+
+        if x == x1 && y == y1 { break; }
+
+        let e2 = 2 * err;
+        if e2 > -dy {
+            err -= dy;
+            x += sx;
+        }
+        if e2 < dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+fn draw_line(canvas: &mut Canvas, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+    let dx = (x1 - x0).abs();
+    let dy = (y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+
+    let mut err = dx - dy;
+    let mut x = x0;
+    let mut y = y0;
+
+    loop {
+        if x >= 0 && x < canvas.width as i32 && y >= 0 && y < canvas.height as i32 {
+            canvas.set_pixel(x as usize, y as usize, color);
+        }
+
+        if x == x1 && y == y1 { break; }
+
+        let e2 = 2 * err;
+        if e2 > -dy {
+            err -= dy;
+            x += sx;
+        }
+        if e2 < dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+fn draw_circle(canvas: &mut Canvas, cx: i32, cy: i32, radius: i32, color: Color) {
+    let r2 = radius as i64 * radius as i64;
+
+    // Only visit the part of the bounding box that lies on the canvas
+    for y in (-radius).max(-cy)..=radius.min(canvas.height as i32 - 1 - cy) {
+        for x in (-radius).max(-cx)..=radius.min(canvas.width as i32 - 1 - cx) {
+            if (x as i64).pow(2) + (y as i64).pow(2) <= r2 {
+                canvas.set_pixel((cx + x) as usize, (cy + y) as usize, color);
+            }
+        }
+    }
+}
+
+fn draw_rectangle(canvas: &mut Canvas, cx: i32, cy: i32, half_size: i32, color: Color) {
+    let x_min = (cx - half_size).max(0) as usize;
+    let x_max = ((cx + half_size).min(canvas.width as i32 - 1) + 1) as usize;
+    let y_min = (cy - half_size).max(0) as usize;
+    let y_max = ((cy + half_size).min(canvas.height as i32 - 1) + 1) as usize;
+
+    for y in y_min..y_max {
+        for x in x_min..x_max {
+            canvas.set_pixel(x, y, color);
+        }
+    }
+}
+
+fn draw_rect_preview(canvas: &mut Canvas, cx: i32, cy: i32, hx: i32, hy: i32, color: Color) {
+    let x_min = (cx - hx).max(0) as usize;
+    let x_max = ((cx + hx).min(canvas.width as i32 - 1) + 1) as usize;
+    let y_min = (cy - hy).max(0) as usize;
+    let y_max = ((cy + hy).min(canvas.height as i32 - 1) + 1) as usize;
+
+    for y in y_min..y_max {
+        for x in x_min..x_max {
+            canvas.set_pixel(x, y, color);
+        }
+    }
+}
+
+fn draw_brush_stroke(canvas: &mut Canvas, x: usize, y: usize, thickness: usize, color: Color) {
+    let t = thickness as i32;
+    let x = x as i32;
+    let y = y as i32;
+    
+    for dy in 0..t {
+        for dx in 0..t {
+            let px = x + dx - t / 2;
+            let py = y + dy - t / 2;
+            if px >= 0 && px < canvas.width as i32 && py >= 0 && py < canvas.height as i32 {
+                canvas.set_pixel(px as usize, py as usize, color);
+            }
+        }
+    }
+}
+
+// Shape tool settings, changed from the shape mode status bar
+#[derive(Clone, Copy, PartialEq)]
+enum ShapeKind {
+    Ellipse,
+    Rectangle,
+    RoundedRectangle,
+    // Regular polygon or star inscribed in the dragged box
+    Polygon,
+    Star,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ShapeStyle {
+    Outline,
+    Fill,
+    // Outline in the current colour around a fill in the secondary colour
+    OutlineAndFill,
+}
+
+#[derive(Clone, Copy)]
+struct ShapeOptions {
+    kind: ShapeKind,
+    style: ShapeStyle,
+    corner_radius: i32,
+    // Sides of a polygon, tips of a star
+    points: usize,
+    // Keep width and height equal (perfect circle or square)
+    constrain: bool,
+    antialias: bool,
+}
+
+impl Default for ShapeOptions {
+    fn default() -> Self {
+        ShapeOptions { kind: ShapeKind::Ellipse, style: ShapeStyle::Fill, corner_radius: 3, points: 5, constrain: false, antialias: false }
+    }
+}
+
+impl ShapeOptions {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            ShapeKind::Ellipse => "Ellipse",
+            ShapeKind::Rectangle => "Rectangle",
+            ShapeKind::RoundedRectangle => "Rounded rectangle",
+            ShapeKind::Polygon => "Polygon",
+            ShapeKind::Star => "Star",
+        }
+    }
+
+    // `[` / `]` change the corner radius or the number of points, whichever the shape has
+    fn adjust(&mut self, delta: i32) {
+        match self.kind {
+            ShapeKind::RoundedRectangle => self.corner_radius = (self.corner_radius + delta).clamp(0, 64),
+            ShapeKind::Polygon | ShapeKind::Star => self.points = (self.points as i32 + delta).clamp(3, 32) as usize,
+            _ => {}
+        }
+    }
+
+    fn label(&self) -> String {
+        let style = match self.style {
+            ShapeStyle::Outline => "Outline",
+            ShapeStyle::Fill => "Fill",
+            ShapeStyle::OutlineAndFill => "Outline + secondary fill",
+        };
+        let setting = match self.kind {
+            ShapeKind::RoundedRectangle => format!(" | Corner radius {} ([ ])", self.corner_radius),
+            ShapeKind::Polygon => format!(" | Sides {} ([ ])", self.points),
+            ShapeKind::Star => format!(" | Points {} ([ ])", self.points),
+            _ => String::new(),
+        };
+        format!(
+            "{} (E/R/O/P/S) | {} (F){} | {} (1, or hold Shift/Ctrl) | {} (A)",
+            self.name(),
+            style,
+            setting,
+            if self.constrain { "1:1" } else { "Free" },
+            smoothing_label(self.antialias)
+        )
+    }
+}
+
+// Moves `end` so the box from `start` to it is square, growing the shorter side
+fn constrain_square(start: (i32, i32), end: (i32, i32)) -> (i32, i32) {
+    let side = (end.0 - start.0).abs().max((end.1 - start.1).abs());
+    let sx = if end.0 < start.0 { -1 } else { 1 };
+    let sy = if end.1 < start.1 { -1 } else { 1 };
+    (start.0 + sx * side, start.1 + sy * side)
+}
+
+// First-quadrant points of a midpoint ellipse with radii rx/ry, from (0, ry) to (rx, 0).
+// Decision variables are scaled by 4 to stay in integers.
+fn ellipse_quadrant(rx: i32, ry: i32) -> Vec<(i32, i32)> {
+    let (rx2, ry2) = (rx as i64 * rx as i64, ry as i64 * ry as i64);
+    let (mut x, mut y) = (0i64, ry as i64);
+    let mut dx = 0;
+    let mut dy = 2 * rx2 * y;
+    let mut points = Vec::new();
+
+    // Region 1: slope above -1, x steps every iteration
+    let mut d = 4 * ry2 - 4 * rx2 * ry as i64 + rx2;
+    while dx < dy {
+        points.push((x as i32, y as i32));
+        x += 1;
+        dx += 2 * ry2;
+        if d < 0 {
+            d += 4 * (dx + ry2);
+        } else {
+            y -= 1;
+            dy -= 2 * rx2;
+            d += 4 * (dx - dy + ry2);
+        }
+    }
+
+    // Region 2: y steps every iteration
+    let mut d = ry2 * (2 * x + 1) * (2 * x + 1) + 4 * rx2 * (y - 1) * (y - 1) - 4 * rx2 * ry2;
+    while y >= 0 {
+        points.push((x as i32, y as i32));
+        y -= 1;
+        dy -= 2 * rx2;
+        if d > 0 {
+            d += 4 * (rx2 - dy);
+        } else {
+            x += 1;
+            dx += 2 * ry2;
+            d += 4 * (dx - dy + rx2);
+        }
+    }
+
+    // Very flat ellipses leave region 2 before reaching the tip
+    let mut tip = points.last().map_or(0, |p| p.0);
+    while tip < rx {
+        tip += 1;
+        points.push((tip, 0));
+    }
+    points
+}
+
+// Rectangles, rounded rectangles and ellipses are all a box with elliptical corners:
+// radii of zero give a rectangle, radii of half the box give an ellipse. An even-sized
+// ellipse gets a one-pixel straight run in the middle of each side, so it fills its box.
+struct ShapeGeometry {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    rx: i32,
+    ry: i32,
+    quadrant: Vec<(i32, i32)>,
+}
+
+impl ShapeGeometry {
+    fn new(corner_a: (i32, i32), corner_b: (i32, i32), kind: ShapeKind, corner_radius: i32) -> Self {
+        let (x0, x1) = (corner_a.0.min(corner_b.0), corner_a.0.max(corner_b.0));
+        let (y0, y1) = (corner_a.1.min(corner_b.1), corner_a.1.max(corner_b.1));
+        let (half_w, half_h) = ((x1 - x0) / 2, (y1 - y0) / 2);
+        let (rx, ry) = match kind {
+            ShapeKind::Ellipse => (half_w, half_h),
+            ShapeKind::RoundedRectangle => {
+                let r = corner_radius.max(0);
+                (r.min(half_w), r.min(half_h))
+            }
+            _ => (0, 0),
+        };
+        ShapeGeometry { x0, y0, x1, y1, rx, ry, quadrant: ellipse_quadrant(rx, ry) }
+    }
+
+    // Horizontal runs (y, x_start, x_end) covering the inside of the shape
+    fn spans(&self) -> Vec<(i32, i32, i32)> {
+        let mut widths = vec![0; self.ry as usize + 1];
+        for &(qx, qy) in &self.quadrant {
+            widths[qy as usize] = widths[qy as usize].max(qx);
+        }
+        let (left, right) = (self.x0 + self.rx, self.x1 - self.rx);
+        let (top, bottom) = (self.y0 + self.ry, self.y1 - self.ry);
+        (self.y0..=self.y1)
+            .map(|y| {
+                let qy = (top - y).max(y - bottom).max(0) as usize;
+                (y, left - widths[qy], right + widths[qy])
+            })
+            .collect()
+    }
+
+    fn outline(&self) -> Vec<(i32, i32)> {
+        let (left, right) = (self.x0 + self.rx, self.x1 - self.rx);
+        let (top, bottom) = (self.y0 + self.ry, self.y1 - self.ry);
+        let mut points = Vec::new();
+        for &(qx, qy) in &self.quadrant {
+            points.extend([(left - qx, top - qy), (right + qx, top - qy), (left - qx, bottom + qy), (right + qx, bottom + qy)]);
+        }
+        for x in left..=right {
+            points.extend([(x, self.y0), (x, self.y1)]);
+        }
+        for y in top..=bottom {
+            points.extend([(self.x0, y), (self.x1, y)]);
+        }
+        points
+    }
+}
+
+// How far the inner corners of a star sit from its centre, relative to the tips
+const STAR_INNER_RATIO: f64 = 0.5;
+
+// Corners of a regular polygon (or a star with `points` tips) inscribed in the box
+// between two corners, starting at the top
+fn regular_polygon(corner_a: (i32, i32), corner_b: (i32, i32), points: usize, star: bool) -> Vec<(i32, i32)> {
+    let (cx, cy) = ((corner_a.0 + corner_b.0) as f64 / 2.0, (corner_a.1 + corner_b.1) as f64 / 2.0);
+    let (rx, ry) = ((corner_a.0 - corner_b.0).abs() as f64 / 2.0, (corner_a.1 - corner_b.1).abs() as f64 / 2.0);
+    let count = if star { points * 2 } else { points };
+    (0..count)
+        .map(|i| {
+            let angle = -std::f64::consts::FRAC_PI_2 + i as f64 * std::f64::consts::TAU / count as f64;
+            let scale = if star && i % 2 == 1 { STAR_INNER_RATIO } else { 1.0 };
+            ((cx + rx * scale * angle.cos()).round() as i32, (cy + ry * scale * angle.sin()).round() as i32)
+        })
+        .collect()
+}
+
+// Even-odd scanline fill of the polygon through `points`, edges included
+fn fill_polygon(canvas: &mut Canvas, points: &[(i32, i32)], color: Color) {
+    let (w, h) = (canvas.width as i32, canvas.height as i32);
+    let top = points.iter().map(|p| p.1).min().unwrap_or(0).max(0);
+    let bottom = points.iter().map(|p| p.1).max().unwrap_or(-1).min(h - 1);
+    let edges: Vec<((i32, i32), (i32, i32))> = (0..points.len()).map(|i| (points[i], points[(i + 1) % points.len()])).collect();
+
+    let mut crossings: Vec<f64> = Vec::new();
+    for y in top..=bottom {
+        crossings.clear();
+        for &(a, b) in &edges {
+            // Half-open in y so a vertex shared by two edges is counted once
+            if (a.1 <= y) != (b.1 <= y) {
+                crossings.push(a.0 as f64 + (y - a.1) as f64 * (b.0 - a.0) as f64 / (b.1 - a.1) as f64);
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for pair in crossings.chunks_exact(2) {
+            let x_start = (pair[0].ceil() as i32).max(0);
+            let x_end = (pair[1].floor() as i32).min(w - 1);
+            for x in x_start..=x_end {
+                canvas.set_pixel(x as usize, y as usize, color);
+            }
+        }
+    }
+    for (a, b) in edges {
+        draw_line(canvas, a.0, a.1, b.0, b.1, color);
+    }
+}
+
+// Chains brush lines through `points`. A closed path also joins the last point back to
+// the first and is filled according to `style`; `fill_color` is used by outline-and-fill.
+fn draw_path(canvas: &mut Canvas, points: &[(i32, i32)], closed: bool, style: ShapeStyle, thickness: usize, color: Color, fill_color: Color) {
+    if closed && style != ShapeStyle::Outline {
+        fill_polygon(canvas, points, if style == ShapeStyle::Fill { color } else { fill_color });
+    }
+    if !closed || style != ShapeStyle::Fill {
+        for pair in points.windows(2) {
+            draw_line_with_brush(canvas, pair[0].0, pair[0].1, pair[1].0, pair[1].1, thickness, color);
+        }
+        if closed && points.len() > 2 {
+            let (first, last) = (points[0], points[points.len() - 1]);
+            draw_line_with_brush(canvas, last.0, last.1, first.0, first.1, thickness, color);
+        }
+    }
+}
+
+// Draws the shape spanning the box between two corners. Outlines use the brush
+// thickness; `fill_color` is only used by the outline-and-fill style.
+fn draw_shape(canvas: &mut Canvas, corner_a: (i32, i32), corner_b: (i32, i32), options: ShapeOptions, thickness: usize, color: Color, fill_color: Color) {
+    if options.antialias {
+        draw_shape_smooth(canvas, corner_a, corner_b, options, thickness, color, fill_color);
+        return;
+    }
+    if matches!(options.kind, ShapeKind::Polygon | ShapeKind::Star) {
+        let points = regular_polygon(corner_a, corner_b, options.points, options.kind == ShapeKind::Star);
+        draw_path(canvas, &points, true, options.style, thickness, color, fill_color);
+        return;
+    }
+    let shape = ShapeGeometry::new(corner_a, corner_b, options.kind, options.corner_radius);
+    let (w, h) = (canvas.width as i32, canvas.height as i32);
+
+    if options.style != ShapeStyle::Outline {
+        let fill = if options.style == ShapeStyle::Fill { color } else { fill_color };
+        for (y, x_start, x_end) in shape.spans() {
+            if y < 0 || y >= h {
+                continue;
+            }
+            for x in x_start.max(0)..=x_end.min(w - 1) {
+                canvas.set_pixel(x as usize, y as usize, fill);
+            }
+        }
+    }
+    if options.style != ShapeStyle::Fill {
+        for (x, y) in shape.outline() {
+            if x >= 0 && x < w && y >= 0 && y < h {
+                draw_brush_stroke(canvas, x as usize, y as usize, thickness, color);
+            }
+        }
+    }
+}
+
+// Per-pixel coverage (0-1) of an anti-aliased stroke or shape. Overlapping parts keep the
+// highest coverage instead of adding up, and the result is blended over the pixels
+// underneath in one go. Only the area drawn into is stored, growing as the shape does.
+struct Coverage {
+    canvas_width: usize,
+    canvas_height: usize,
+    // Stored area as (x, y, width, height) in canvas pixels
+    window: (usize, usize, usize, usize),
+    values: Vec<f32>,
+    // Area changed since the last `apply`, as (x_min, y_min, x_max, y_max)
+    dirty: Option<(usize, usize, usize, usize)>,
+}
+
+impl Coverage {
+    fn new(canvas_width: usize, canvas_height: usize) -> Self {
+        Coverage { canvas_width, canvas_height, window: (0, 0, 0, 0), values: Vec::new(), dirty: None }
+    }
+
+    // Makes room for (x0, y0)-(x1, y1). The window at least doubles on the sides it grows
+    // towards, so a brush stroke that keeps extending it only copies now and then.
+    fn reserve(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        let (wx, wy, ww, wh) = self.window;
+        if x0 >= wx && y0 >= wy && x1 < wx + ww && y1 < wy + wh {
+            return;
+        }
+        let (nx0, ny0, nx1, ny1) = if self.values.is_empty() {
+            (x0, y0, x1, y1)
+        } else {
+            (
+                if x0 < wx { x0.min(wx.saturating_sub(ww)) } else { wx },
+                if y0 < wy { y0.min(wy.saturating_sub(wh)) } else { wy },
+                if x1 >= wx + ww { x1.max(wx + 2 * ww - 1).min(self.canvas_width - 1) } else { wx + ww - 1 },
+                if y1 >= wy + wh { y1.max(wy + 2 * wh - 1).min(self.canvas_height - 1) } else { wy + wh - 1 },
+            )
+        };
+        let (nw, nh) = (nx1 - nx0 + 1, ny1 - ny0 + 1);
+        let mut values = vec![0.0; nw * nh];
+        for row in 0..wh {
+            let start = (wy + row - ny0) * nw + wx - nx0;
+            values[start..start + ww].copy_from_slice(&self.values[row * ww..(row + 1) * ww]);
+        }
+        self.window = (nx0, ny0, nw, nh);
+        self.values = values;
+    }
+
+    fn add(&mut self, x: i32, y: i32, amount: f32) {
+        if x < 0 || y < 0 || x as usize >= self.canvas_width || y as usize >= self.canvas_height || amount <= 0.0 {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        self.reserve(x, y, x, y);
+        let (wx, wy, ww, _) = self.window;
+        let value = &mut self.values[(y - wy) * ww + x - wx];
+        *value = value.max(amount.min(1.0));
+        self.dirty = Some(match self.dirty {
+            Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+            None => (x, y, x, y),
+        });
+    }
+
+    // Xiaolin Wu's line: each step shares its coverage between the two pixels on either
+    // side of the ideal line
+    fn wu_line(&mut self, a: (f64, f64), b: (f64, f64)) {
+        let steep = (b.1 - a.1).abs() > (b.0 - a.0).abs();
+        let (mut a, mut b) = if steep { ((a.1, a.0), (b.1, b.0)) } else { (a, b) };
+        if a.0 > b.0 {
+            std::mem::swap(&mut a, &mut b);
+        }
+        let gradient = if b.0 == a.0 { 0.0 } else { (b.1 - a.1) / (b.0 - a.0) };
+        for x in a.0.round() as i32..=b.0.round() as i32 {
+            let y = a.1 + gradient * (x as f64 - a.0);
+            let (row, frac) = (y.floor() as i32, (y - y.floor()) as f32);
+            for (row, amount) in [(row, 1.0 - frac), (row + 1, frac)] {
+                if steep {
+                    self.add(row, x, amount);
+                } else {
+                    self.add(x, row, amount);
+                }
+            }
+        }
+    }
+
+    // Soft round brush of the given radius swept from `a` to `b`, fading out over one pixel
+    fn capsule(&mut self, a: (f64, f64), b: (f64, f64), radius: f64) {
+        let reach = radius + 1.0;
+        let x_range = ((a.0.min(b.0) - reach).floor().max(0.0) as i32)..=((a.0.max(b.0) + reach).ceil().min(self.canvas_width as f64 - 1.0) as i32);
+        let y_range = ((a.1.min(b.1) - reach).floor().max(0.0) as i32)..=((a.1.max(b.1) + reach).ceil().min(self.canvas_height as f64 - 1.0) as i32);
+        if x_range.is_empty() || y_range.is_empty() {
+            return;
+        }
+        self.reserve(*x_range.start() as usize, *y_range.start() as usize, *x_range.end() as usize, *y_range.end() as usize);
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let length2 = dx * dx + dy * dy;
+        for y in y_range {
+            for x in x_range.clone() {
+                let (px, py) = (x as f64, y as f64);
+                let t = if length2 == 0.0 { 0.0 } else { (((px - a.0) * dx + (py - a.1) * dy) / length2).clamp(0.0, 1.0) };
+                let distance = (px - a.0 - t * dx).hypot(py - a.1 - t * dy);
+                self.add(x, y, (radius + 0.5 - distance).clamp(0.0, 1.0) as f32);
+            }
+        }
+    }
+
+    // A Wu line for one-pixel strokes, the soft round brush for thicker ones
+    fn stroke(&mut self, a: (i32, i32), b: (i32, i32), thickness: usize) {
+        let (a, b) = ((a.0 as f64, a.1 as f64), (b.0 as f64, b.1 as f64));
+        if thickness <= 1 {
+            self.wu_line(a, b);
+        } else {
+            self.capsule(a, b, thickness as f64 / 2.0);
+        }
+    }
+
+    // Coverage of each pixel in the box by `inside`, from a 4x4 grid of samples
+    fn fill_with(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, inside: impl Fn(f64, f64) -> bool) {
+        const GRID: usize = 4;
+        let offset = |i: usize| (i as f64 + 0.5) / GRID as f64 - 0.5;
+        let (x0, y0, x1, y1) = (x0.max(0), y0.max(0), x1.min(self.canvas_width as i32 - 1), y1.min(self.canvas_height as i32 - 1));
+        if x0 > x1 || y0 > y1 {
+            return;
+        }
+        self.reserve(x0 as usize, y0 as usize, x1 as usize, y1 as usize);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let hits = (0..GRID * GRID).filter(|&i| inside(x as f64 + offset(i % GRID), y as f64 + offset(i / GRID))).count();
+                self.add(x, y, hits as f32 / (GRID * GRID) as f32);
+            }
+        }
+    }
+
+    // Writes `color` blended over `base` (the canvas itself if None) into `canvas`, for
+    // the pixels whose coverage changed since the last call
+    fn apply(&mut self, canvas: &mut Canvas, base: Option<&Canvas>, color: Color) {
+        let Some((x0, y0, x1, y1)) = self.dirty.take() else {
+            return;
+        };
+        let (wx, wy, ww, _) = self.window;
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let amount = self.values[(y - wy) * ww + x - wx];
+                if amount > 0.0 {
+                    let alpha = (color[3] as f32 * amount).round() as u8;
+                    let under = base.unwrap_or(canvas).get_pixel(x, y);
+                    canvas.set_pixel(x, y, blend_over(under, [color[0], color[1], color[2], alpha], 100));
+                }
+            }
+        }
+    }
+}
+
+fn smoothing_label(antialias: bool) -> &'static str {
+    if antialias { "Anti-aliased" } else { "Pixel-perfect" }
+}
+
+// Whether (px, py), relative to the centre, is inside a box of half-size hw x hh whose
+// corners are rounded with radii rx/ry (half the box gives an ellipse)
+fn rounded_box_contains(px: f64, py: f64, hw: f64, hh: f64, rx: f64, ry: f64) -> bool {
+    let (px, py) = (px.abs(), py.abs());
+    if px > hw || py > hh {
+        return false;
+    }
+    let (dx, dy) = (px - (hw - rx), py - (hh - ry));
+    if dx <= 0.0 || dy <= 0.0 {
+        return true;
+    }
+    (dx / rx).powi(2) + (dy / ry).powi(2) <= 1.0
+}
+
+// Even-odd test against the polygon through `points`
+fn polygon_contains(points: &[(i32, i32)], px: f64, py: f64) -> bool {
+    let mut inside = false;
+    for i in 0..points.len() {
+        let (a, b) = (points[i], points[(i + 1) % points.len()]);
+        if (a.1 as f64 > py) != (b.1 as f64 > py) {
+            let x = a.0 as f64 + (py - a.1 as f64) * (b.0 - a.0) as f64 / (b.1 - a.1) as f64;
+            if px < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+// Anti-aliased `draw_path`
+fn draw_path_smooth(canvas: &mut Canvas, points: &[(i32, i32)], closed: bool, style: ShapeStyle, thickness: usize, color: Color, fill_color: Color) {
+    if closed && style != ShapeStyle::Outline && points.len() > 2 {
+        let mut coverage = Coverage::new(canvas.width, canvas.height);
+        let x0 = points.iter().map(|p| p.0).min().unwrap_or(0);
+        let x1 = points.iter().map(|p| p.0).max().unwrap_or(0);
+        let y0 = points.iter().map(|p| p.1).min().unwrap_or(0);
+        let y1 = points.iter().map(|p| p.1).max().unwrap_or(0);
+        coverage.fill_with(x0, y0, x1, y1, |px, py| polygon_contains(points, px, py));
+        coverage.apply(canvas, None, if style == ShapeStyle::Fill { color } else { fill_color });
+    }
+    if !closed || style != ShapeStyle::Fill {
+        let mut coverage = Coverage::new(canvas.width, canvas.height);
+        for pair in points.windows(2) {
+            coverage.stroke(pair[0], pair[1], thickness);
+        }
+        if closed && points.len() > 2 {
+            coverage.stroke(points[points.len() - 1], points[0], thickness);
+        }
+        coverage.apply(canvas, None, color);
+    }
+}
+
+// Anti-aliased `draw_shape`. The box edges sit half a pixel outside the corner pixels,
+// and outlines are stroked inwards by the brush thickness.
+fn draw_shape_smooth(canvas: &mut Canvas, corner_a: (i32, i32), corner_b: (i32, i32), options: ShapeOptions, thickness: usize, color: Color, fill_color: Color) {
+    if matches!(options.kind, ShapeKind::Polygon | ShapeKind::Star) {
+        let points = regular_polygon(corner_a, corner_b, options.points, options.kind == ShapeKind::Star);
+        draw_path_smooth(canvas, &points, true, options.style, thickness, color, fill_color);
+        return;
+    }
+    let (x0, x1) = (corner_a.0.min(corner_b.0), corner_a.0.max(corner_b.0));
+    let (y0, y1) = (corner_a.1.min(corner_b.1), corner_a.1.max(corner_b.1));
+    let (cx, cy) = ((x0 + x1) as f64 / 2.0, (y0 + y1) as f64 / 2.0);
+    let (hw, hh) = ((x1 - x0 + 1) as f64 / 2.0, (y1 - y0 + 1) as f64 / 2.0);
+    let (rx, ry) = match options.kind {
+        ShapeKind::Ellipse => (hw, hh),
+        ShapeKind::RoundedRectangle => {
+            let r = options.corner_radius.max(0) as f64 + 0.5;
+            (r.min(hw), r.min(hh))
+        }
+        _ => (0.0, 0.0),
+    };
+    let inside = |px: f64, py: f64, inset: f64| {
+        rounded_box_contains(px - cx, py - cy, hw - inset, hh - inset, (rx - inset).max(0.0), (ry - inset).max(0.0))
+    };
+
+    if options.style != ShapeStyle::Outline {
+        let mut coverage = Coverage::new(canvas.width, canvas.height);
+        coverage.fill_with(x0, y0, x1, y1, |px, py| inside(px, py, 0.0));
+        coverage.apply(canvas, None, if options.style == ShapeStyle::Fill { color } else { fill_color });
+    }
+    if options.style != ShapeStyle::Fill {
+        let inset = thickness as f64;
+        let mut coverage = Coverage::new(canvas.width, canvas.height);
+        coverage.fill_with(x0, y0, x1, y1, |px, py| inside(px, py, 0.0) && !inside(px, py, inset));
+        coverage.apply(canvas, None, color);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LineMode {
+    Single,
+    // Keeps adding segments until the path is finished
+    Polyline,
+    // Clicked as start, end, then the control point(s)
+    QuadraticBezier,
+    CubicBezier,
+    // Catmull-Rom spline through every clicked point
+    Spline,
+}
+
+impl LineMode {
+    fn name(&self) -> &'static str {
+        match self {
+            LineMode::Single => "Single line",
+            LineMode::Polyline => "Polyline",
+            LineMode::QuadraticBezier => "Quadratic Bézier",
+            LineMode::CubicBezier => "Cubic Bézier",
+            LineMode::Spline => "Spline",
+        }
+    }
+
+    fn is_curve(&self) -> bool {
+        matches!(self, LineMode::QuadraticBezier | LineMode::CubicBezier | LineMode::Spline)
+    }
+
+    fn max_points(&self) -> Option<usize> {
+        match self {
+            LineMode::Single => Some(2),
+            LineMode::QuadraticBezier => Some(3),
+            LineMode::CubicBezier => Some(4),
+            LineMode::Polyline | LineMode::Spline => None,
+        }
+    }
+}
+
+// Line tool settings, changed from the line mode status bar
+#[derive(Clone, Copy)]
+struct LineOptions {
+    mode: LineMode,
+    // Polyline only: join the last point back to the first and fill according to `style`
+    closed: bool,
+    style: ShapeStyle,
+    antialias: bool,
+}
+
+impl Default for LineOptions {
+    fn default() -> Self {
+        LineOptions { mode: LineMode::Single, closed: false, style: ShapeStyle::Outline, antialias: false }
+    }
+}
+
+impl LineOptions {
+    fn label(&self) -> String {
+        let mut label = format!("{} (P polyline, B Bézier, S spline)", self.mode.name());
+        if self.mode == LineMode::Polyline {
+            let style = match self.style {
+                ShapeStyle::Outline => "Outline",
+                ShapeStyle::Fill => "Fill",
+                ShapeStyle::OutlineAndFill => "Outline + secondary fill",
+            };
+            label += &format!(" | {} (C) | {} (F)", if self.closed { "Closed polygon" } else { "Open" }, style);
+        }
+        label + &format!(" | {} (A)", smoothing_label(self.antialias))
+    }
+
+    fn history_name(&self) -> &'static str {
+        match self.mode {
+            LineMode::Single => "Line",
+            LineMode::Polyline if self.closed => "Polygon",
+            LineMode::Polyline => "Polyline",
+            LineMode::QuadraticBezier | LineMode::CubicBezier => "Curve",
+            LineMode::Spline => "Spline",
+        }
+    }
+
+    // Draws the clicked points in the current mode
+    fn draw(&self, canvas: &mut Canvas, points: &[(i32, i32)], thickness: usize, color: Color, fill_color: Color) {
+        let spans = match self.mode {
+            LineMode::Single | LineMode::Polyline => {
+                // A single line is an open path, always one pixel wide
+                let (closed, thickness) = if self.mode == LineMode::Single { (false, 1) } else { (self.closed, thickness) };
+                if self.antialias {
+                    draw_path_smooth(canvas, points, closed, self.style, thickness, color, fill_color);
+                } else {
+                    draw_path(canvas, points, closed, self.style, thickness, color, fill_color);
+                }
+                return;
+            }
+            LineMode::QuadraticBezier | LineMode::CubicBezier => {
+                // Clicks are start, end, controls; the curve wants start, controls, end
+                let mut controls: Vec<(f64, f64)> = points.iter().map(|&(x, y)| (x as f64, y as f64)).collect();
+                if controls.len() > 2 {
+                    let end = controls.remove(1);
+                    controls.push(end);
+                }
+                vec![bezier_points(&controls)]
+            }
+            // Span by span, so every clicked point stays on the curve
+            LineMode::Spline => catmull_rom_spans(points),
+        };
+
+        if self.antialias {
+            let mut coverage = Coverage::new(canvas.width, canvas.height);
+            for pair in spans.iter().flat_map(|span| span.windows(2)) {
+                coverage.capsule(pair[0], pair[1], thickness as f64 / 2.0);
+            }
+            coverage.apply(canvas, None, color);
+        } else {
+            for span in spans {
+                draw_curve(canvas, &span, thickness, color);
+            }
+        }
+    }
+}
+
+// Samples along a Bézier curve of any degree (de Casteljau). A degree-n curve moves at
+// most n times its longest control leg per unit of t, so this step count keeps samples
+// within half a pixel of each other.
+fn bezier_points(controls: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    if controls.is_empty() {
+        return Vec::new();
+    }
+    let longest = controls.windows(2).map(|w| (w[1].0 - w[0].0).hypot(w[1].1 - w[0].1)).fold(0.0, f64::max);
+    let degree = (controls.len() - 1) as f64;
+    let steps = (longest * degree * 2.0).ceil().max(1.0) as usize;
+    (0..=steps)
+        .map(|i| {
+            let t = i as f64 / steps as f64;
+            let mut level = controls.to_vec();
+            while level.len() > 1 {
+                level = level.windows(2).map(|w| (w[0].0 + (w[1].0 - w[0].0) * t, w[0].1 + (w[1].1 - w[0].1) * t)).collect();
+            }
+            level[0]
+        })
+        .collect()
+}
+
+// Samples along each span of a uniform Catmull-Rom spline through `points`, converted to
+// the equivalent cubic Bézier. The end points are repeated so the curve reaches them.
+fn catmull_rom_spans(points: &[(i32, i32)]) -> Vec<Vec<(f64, f64)>> {
+    let p: Vec<(f64, f64)> = points.iter().map(|&(x, y)| (x as f64, y as f64)).collect();
+    (0..p.len().saturating_sub(1))
+        .map(|i| {
+            let (p0, p1, p2, p3) = (p[i.saturating_sub(1)], p[i], p[i + 1], p[(i + 2).min(p.len() - 1)]);
+            let c1 = (p1.0 + (p2.0 - p0.0) / 6.0, p1.1 + (p2.1 - p0.1) / 6.0);
+            let c2 = (p2.0 - (p3.0 - p1.0) / 6.0, p2.1 - (p3.1 - p1.1) / 6.0);
+            bezier_points(&[p1, c1, c2, p2])
+        })
+        .collect()
+}
+
+// Stamps the brush on every pixel the samples pass through. The corner pixel of an
+// L-shaped step is skipped, so one-pixel curves don't look doubled on diagonals; the
+// first and last pixels are always kept.
+fn draw_curve(canvas: &mut Canvas, samples: &[(f64, f64)], thickness: usize, color: Color) {
+    let mut pixels: Vec<(i32, i32)> = Vec::new();
+    for &(x, y) in samples {
+        let p = (x.round() as i32, y.round() as i32);
+        if pixels.last() == Some(&p) {
+            continue;
+        }
+        if let [.., a, _] = pixels[..] && (p.0 - a.0).abs() == 1 && (p.1 - a.1).abs() == 1 {
+            pixels.pop();
+        }
+        pixels.push(p);
+    }
+    for (x, y) in pixels {
+        if x >= 0 && x < canvas.width as i32 && y >= 0 && y < canvas.height as i32 {
+            draw_brush_stroke(canvas, x as usize, y as usize, thickness, color);
+        }
+    }
+}
+
+// Two clicks on the same pixel within this time finish a polyline
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+
+// Fill tool settings, changed from the fill mode status bar
+#[derive(Clone, Copy, Default)]
+struct FillOptions {
+    tolerance: u8,
+    // 8-connected: also spread to diagonal neighbours
+    diagonal: bool,
+    // Replace the colour everywhere instead of only the connected area
+    global: bool,
+}
+
+impl FillOptions {
+    fn label(&self) -> String {
+        format!(
+            "Tolerance {}% of RGBA distance (T) | {}-connected (4/8) | {} (G)",
+            self.tolerance,
+            if self.diagonal { 8 } else { 4 },
+            if self.global { "Replace everywhere" } else { "Contiguous" }
+        )
+    }
+}
+
+fn flood_fill(canvas: &mut Canvas, x: i32, y: i32, new_color: Color, within: Option<&Selection>, options: FillOptions) {
+    let w = canvas.width as i32;
+    let h = canvas.height as i32;
+    if x < 0 || y < 0 || x >= w || y >= h || within.is_some_and(|s| !s.contains(x, y)) {
+        return;
+    }
+
+    let target = canvas.get_pixel(x as usize, y as usize);
+    if target == new_color && options.tolerance == 0 {
+        return;
+    }
+
+    let region = if options.global {
+        let mut region = matching_pixels(canvas, x, y, options.tolerance);
+        if let Some(within) = within {
+            region.mask.iter_mut().zip(&within.mask).for_each(|(inside, &allowed)| *inside &= allowed);
+        }
+        region
+    } else {
+        connected_region(canvas, x, y, options.tolerance, options.diagonal, within)
+    };
+    for (pixel, &inside) in canvas.pixels.iter_mut().zip(&region.mask) {
+        if inside {
+            *pixel = new_color;
+        }
+    }
+}
+
+// Euclidean distance between opaque black and transparent white, the furthest apart
+// two colours can be once alpha counts alongside R, G and B
+const MAX_COLOR_DISTANCE: u64 = 510;
+
+// Colours match when their Euclidean distance over R, G, B and A is at most
+// `tolerance` percent of MAX_COLOR_DISTANCE, so 100 matches everything
+fn colors_match(a: Color, b: Color, tolerance: u8) -> bool {
+    let distance: u64 = a.iter().zip(&b).map(|(&p, &q)| (p.abs_diff(q) as u64).pow(2)).sum();
+    distance * 100 * 100 <= (tolerance as u64 * MAX_COLOR_DISTANCE).pow(2)
+}
+
+// Pixels 4-connected (8-connected with `diagonal`) to (x, y) whose colour is within
+// `tolerance` of the start pixel, never leaving `within`. Shared by the fill tool and
+// the magic wand.
+//
+// Scanline fill: each seed is grown into the whole horizontal run of matching pixels,
+// then the rows above and below are scanned across that run (one pixel wider on each
+// side when diagonals count) and only one new seed is queued per run found there.
+fn connected_region(
+    canvas: &Canvas,
+    x: i32,
+    y: i32,
+    tolerance: u8,
+    diagonal: bool,
+    within: Option<&Selection>,
+) -> Selection {
+    let w = canvas.width;
+    let h = canvas.height;
+    let mut region = Selection::empty(w, h);
+    if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 || within.is_some_and(|s| !s.contains(x, y)) {
+        return region;
+    }
+
+    let target = canvas.get_pixel(x as usize, y as usize);
+    let fillable = |mask: &[bool], i: usize| {
+        !mask[i] && colors_match(canvas.pixels[i], target, tolerance) && within.is_none_or(|s| s.mask[i])
+    };
+    let mut seeds = vec![(x as usize, y as usize)];
+
+    while let Some((sx, sy)) = seeds.pop() {
+        let row = sy * w;
+        if !fillable(&region.mask, row + sx) {
+            continue;
+        }
+        let mut left = sx;
+        while left > 0 && fillable(&region.mask, row + left - 1) {
+            left -= 1;
+        }
+        let mut right = sx;
+        while right + 1 < w && fillable(&region.mask, row + right + 1) {
+            right += 1;
+        }
+        region.mask[row + left..=row + right].fill(true);
+
+        let (from, to) = if diagonal { (left.saturating_sub(1), (right + 1).min(w - 1)) } else { (left, right) };
+        for ny in [sy.wrapping_sub(1), sy + 1] {
+            if ny >= h {
+                continue;
+            }
+            let mut nx = from;
+            while nx <= to {
+                if fillable(&region.mask, ny * w + nx) {
+                    seeds.push((nx, ny));
+                    while nx <= to && fillable(&region.mask, ny * w + nx) {
+                        nx += 1;
+                    }
+                } else {
+                    nx += 1;
+                }
+            }
+        }
+    }
+
+    region
+}
+
+// Every pixel on the canvas within `tolerance` of the colour at (x, y)
+fn matching_pixels(canvas: &Canvas, x: i32, y: i32, tolerance: u8) -> Selection {
+    let mut region = Selection::empty(canvas.width, canvas.height);
+    if x < 0 || y < 0 || x >= canvas.width as i32 || y >= canvas.height as i32 {
+        return region;
+    }
+    let target = canvas.get_pixel(x as usize, y as usize);
+    for (inside, &pixel) in region.mask.iter_mut().zip(&canvas.pixels) {
+        *inside = colors_match(pixel, target, tolerance);
+    }
+    region
+}
+
+// Freehand lasso: the path (closed back to its start) is rasterised with
+// draw_line on a canvas with a one pixel border, then everything the border
+// can't reach by flood fill is inside.
+fn lasso_selection(width: usize, height: usize, points: &[(i32, i32)]) -> Selection {
+    let mut outline = Canvas::new_transparent(width + 2, height + 2);
+    let clamp_point = |(x, y): (i32, i32)| (x.clamp(0, width as i32 - 1) + 1, y.clamp(0, height as i32 - 1) + 1);
+    for (i, &start) in points.iter().enumerate() {
+        let (x0, y0) = clamp_point(start);
+        let (x1, y1) = clamp_point(points[(i + 1) % points.len()]);
+        draw_line(&mut outline, x0, y0, x1, y1, WHITE);
+    }
+
+    let outside = connected_region(&outline, 0, 0, 0, false, None);
+    let mut selection = Selection::empty(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            selection.mask[y * width + x] = !outside.contains(x as i32 + 1, y as i32 + 1);
+        }
+    }
+    selection
+}
+
+// The pixels along a lasso path as drawn so far, for previewing a drag without
+// building the whole mask
+fn lasso_trail(points: &[(i32, i32)]) -> HashSet<(usize, usize)> {
+    let mut trail = HashSet::new();
+    for (i, &(x0, y0)) in points.iter().enumerate() {
+        let (x1, y1) = points.get(i + 1).copied().unwrap_or((x0, y0));
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1);
+        for t in 0..=steps {
+            let x = x0 + ((x1 - x0) as f64 * t as f64 / steps as f64).round() as i32;
+            let y = y0 + ((y1 - y0) as f64 * t as f64 / steps as f64).round() as i32;
+            trail.insert((x.max(0) as usize, y.max(0) as usize));
+        }
+    }
+    trail
+}
+
+fn prompt(msg: &str) -> String {
+    disable_raw_mode().ok();
+    print!("{}", msg);
+    let _ = io::stdout().flush();
+
+    let mut input = String::new();
+    let _ = io::stdin().read_line(&mut input);
+
+    enable_raw_mode().ok();
+    input.trim().to_string()
+}
+
+// Asks for a fill or wand tolerance. Blank input keeps the old value; anything else
+// that is not 0-100 is reported in the status bar.
+fn prompt_tolerance(tool: &str, status_message: &mut Option<String>) -> Option<u8> {
+    let input = prompt(&format!("{} tolerance, in % of the largest colour distance with alpha counted (0-100): ", tool));
+    if input.is_empty() {
+        return None;
+    }
+    let tolerance = input.trim_end_matches('%').parse::<u8>().ok().filter(|&t| t <= 100);
+    if tolerance.is_none() {
+        *status_message = Some(format!("Tolerance not changed: '{}' is not a number from 0 to 100", input));
+    }
+    tolerance
+}
+
+// A tool's status bar, led by the message from the last action if there is one
+fn status_line(status_message: Option<&String>, text: String) -> Line<'static> {
+    let mut spans = Vec::new();
+    if let Some(message) = status_message {
+        spans.push(Span::styled(format!("{} | ", message), Style::default().fg(RColor::Red).add_modifier(Modifier::BOLD)));
+    }
+    spans.push(Span::raw(text));
+    Line::from(spans)
+}
+
+fn active_layer_locked(layers: &LayerStack) -> bool {
+    if layers.active_layer().locked {
+        prompt(&format!("Layer '{}' is locked (K to unlock). Press Enter to continue...", layers.active_layer().name));
+        return true;
+    }
+    false
+}
+
+fn clear_input_buffer() {
+    while event::poll(Duration::from_millis(0)).ok().unwrap_or(false) {
+        let _ = event::read();
+    }
+}
+
+fn expand_path(path: &str) -> String {
+    if path.starts_with('~') && let Ok(home) = std::env::var("HOME") {
+        return path.replacen('~', &home, 1);
+    }
+    path.to_string()
+}
+
+fn is_png_path(path: &str) -> bool {
+    path.to_lowercase().ends_with(".png")
+}
+
+fn with_default_extension(filename: &str) -> String {
+    let lower = filename.to_lowercase();
+    if lower.ends_with(".rai") || lower.ends_with(".png") {
+        filename.to_string()
+    } else {
+        format!("{}.rai", filename)
+    }
+}
+
+fn save_image(layers: &LayerStack, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+    save_image_with_history(layers, filename, None, None, HistoryStorage::Off)
+}
+
+fn save_image_with_history(
+    layers: &LayerStack,
+    filename: &str,
+    palette: Option<&Palette>,
+    history: Option<&History>,
+    storage: HistoryStorage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let expanded_path = expand_path(filename);
+    
+    if let Some(parent) = std::path::Path::new(&expanded_path).parent() && !parent.as_os_str().is_empty() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let software = format!("Raint {}", RAINT_VERSION);
+    let metadata = [("software", software.as_str())];
+    let embed = storage == HistoryStorage::Embedded && !is_png_path(&expanded_path);
+    let bytes = if is_png_path(&expanded_path) {
+        encode_png(&layers.composite())
+    } else {
+        encode_rai(layers, &metadata, palette.map(|p| p.colors.as_slice()), history.filter(|_| embed))
+    };
+    std::fs::write(&expanded_path, bytes)?;
+
+    // A sidecar left from an earlier save no longer matches, so it is replaced or removed
+    let sidecar = history_sidecar_path(&expanded_path);
+    match history {
+        Some(history) if !embed => std::fs::write(&sidecar, encode_rai(layers, &metadata, None, Some(history)))?,
+        _ if std::path::Path::new(&sidecar).exists() => std::fs::remove_file(&sidecar)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn load_image(filename: &str) -> Result<LayerStack, Box<dyn std::error::Error>> {
+    Ok(load_document(filename)?.layers)
+}
+
+// Where undo history goes when a document is saved. PNG files cannot embed it, so
+// `Embedded` falls back to the sidecar for them.
+#[derive(Clone, Copy, PartialEq)]
+enum HistoryStorage {
+    Off,
+    Embedded,
+    Sidecar,
+}
+
+// The sidecar is a .rai holding the layers and history next to the document; it is
+// only used while its composite still matches the document.
+fn history_sidecar_path(expanded_path: &str) -> String {
+    format!("{}.history", expanded_path)
+}
+
+struct LoadedDocument {
+    layers: LayerStack,
+    // Only .rai files carry a palette
+    palette: Option<Vec<Color>>,
+    history: Option<Vec<u8>>,
+    storage: HistoryStorage,
+}
+
+fn load_document(filename: &str) -> Result<LoadedDocument, Box<dyn std::error::Error>> {
+    let expanded_path = expand_path(filename);
+    let data = std::fs::read(&expanded_path)?;
+
+    let (layers, palette) = if is_png_path(&expanded_path) {
+        (LayerStack::from_canvas(decode_png(&data)?), None)
+    } else {
+        let doc = decode_rai(&data)?;
+        if doc.history.is_some() {
+            return Ok(LoadedDocument {
+                layers: doc.layers,
+                palette: doc.palette,
+                history: doc.history,
+                storage: HistoryStorage::Embedded,
+            });
+        }
+        (doc.layers, doc.palette)
+    };
+
+    let sidecar = std::fs::read(history_sidecar_path(&expanded_path)).ok().and_then(|d| decode_rai(&d).ok());
+    match sidecar {
+        Some(doc) if doc.history.is_some() && doc.layers.composite().pixels == layers.composite().pixels => {
+            Ok(LoadedDocument { layers: doc.layers, palette, history: doc.history, storage: HistoryStorage::Sidecar })
+        }
+        _ => Ok(LoadedDocument { layers, palette, history: None, storage: HistoryStorage::Off }),
+    }
+}
+
+fn save_document(
+    layers: &LayerStack,
+    filename: &str,
+    palette: &Palette,
+    history: Option<&History>,
+    storage: HistoryStorage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    save_image_with_history(layers, filename, Some(palette), history.filter(|_| storage != HistoryStorage::Off), storage)
+}
+
+// Removes saved history from a document before sharing it: the HIST chunk of a .rai
+// and any sidecar file.
+fn strip_history(filename: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let expanded_path = expand_path(filename);
+    let mut stripped = false;
+    if !is_png_path(&expanded_path) {
+        let data = std::fs::read(&expanded_path)?;
+        if let Some(without) = strip_rai_history(&data)? {
+            std::fs::write(&expanded_path, without)?;
+            stripped = true;
+        }
+    }
+    let sidecar = history_sidecar_path(&expanded_path);
+    if std::path::Path::new(&sidecar).exists() {
+        std::fs::remove_file(&sidecar)?;
+        stripped = true;
+    }
+    Ok(stripped)
+}
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// $XDG_DATA_HOME/raint/recovery, falling back to ~/.local/share/raint/recovery
+fn recovery_dir() -> Option<PathBuf> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data_home.join("raint").join("recovery"))
+}
+
+// Recovery files are named after the process writing them, so a second Raint
+// running at the same time neither overwrites nor offers to restore another's work.
+fn recovery_owner_running(path: &Path) -> bool {
+    let pid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.strip_prefix("raint-"))
+        .and_then(|pid| pid.parse::<u32>().ok());
+    match pid {
+        Some(pid) if pid == std::process::id() => true,
+        // Without /proc there is no cheap liveness check, so every file counts as abandoned
+        Some(pid) => Path::new("/proc/self").exists() && Path::new(&format!("/proc/{}", pid)).exists(),
+        None => false,
+    }
+}
+
+// Where this process writes its own recovery file
+fn recovery_path() -> Option<PathBuf> {
+    recovery_dir().map(|dir| dir.join(format!("raint-{}.rai", std::process::id())))
+}
+
+// Recovery files left behind by sessions that are no longer running, newest first
+fn abandoned_recovery_files() -> Vec<(PathBuf, std::time::SystemTime)> {
+    let Some(entries) = recovery_dir().and_then(|dir| std::fs::read_dir(dir).ok()) else {
+        return Vec::new();
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rai") && !recovery_owner_running(path))
+        .map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).unwrap_or(std::time::UNIX_EPOCH);
+            (path, modified)
+        })
+        .collect();
+    files.sort_by_key(|(_, modified)| std::cmp::Reverse(*modified));
+    files
+}
+
+fn write_recovery_file(path: &Path, layers: &LayerStack, palette: &[Color], source: Option<&str>) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let software = format!("Raint {}", RAINT_VERSION);
+    let mut metadata = vec![("software", software.as_str())];
+    if let Some(source) = source {
+        metadata.push(("source", source));
+    }
+    // Written beside the target and renamed so a crash mid-write keeps the previous copy
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, encode_rai(layers, &metadata, Some(palette), None))?;
+    std::fs::rename(&partial, path)
+}
+
+// The last changed state of the document, kept for the panic hook
+struct RecoverySnapshot {
+    layers: LayerStack,
+    palette: Vec<Color>,
+    source: Option<String>,
+    unsaved: bool,
+}
+
+// Writes the canvas to the recovery directory every AUTOSAVE_INTERVAL while it has
+// changes that are not in a saved file. Changes are noticed through the history's
+// current node, which moves on every commit, undo, redo and jump, and by comparing
+// the palette, whose edits are not in the history.
+struct Autosave {
+    path: Option<PathBuf>,
+    snapshot: Arc<Mutex<RecoverySnapshot>>,
+    seen_node: usize,
+    seen_palette: Vec<Color>,
+    // Counts the changes seen, so a write can be matched to the state it holds
+    revision: u64,
+    written_revision: Option<u64>,
+    last_write: std::time::Instant,
+}
+
+impl Autosave {
+    // `unsaved` marks a canvas that starts out with work not in any file, such as a restored recovery
+    fn new(layers: &LayerStack, history: &History, palette: &[Color], source: Option<&String>, unsaved: bool) -> Self {
+        let snapshot = RecoverySnapshot { layers: layers.clone(), palette: palette.to_vec(), source: source.cloned(), unsaved };
+        Autosave {
+            path: recovery_path(),
+            snapshot: Arc::new(Mutex::new(snapshot)),
+            seen_node: history.current,
+            seen_palette: palette.to_vec(),
+            revision: 0,
+            written_revision: if unsaved { None } else { Some(0) },
+            last_write: std::time::Instant::now(),
+        }
+    }
+
+    fn tick(&mut self, layers: &LayerStack, history: &History, palette: &[Color], source: Option<&String>) {
+        if history.current != self.seen_node || palette != self.seen_palette {
+            self.seen_node = history.current;
+            self.seen_palette = palette.to_vec();
+            self.revision += 1;
+            if let Ok(mut snapshot) = self.snapshot.lock() {
+                snapshot.layers.clone_from(layers);
+                snapshot.palette = palette.to_vec();
+                snapshot.source = source.cloned();
+                snapshot.unsaved = true;
+            }
+        }
+        if self.written_revision == Some(self.revision) || self.last_write.elapsed() < AUTOSAVE_INTERVAL {
+            return;
+        }
+        // A failed write leaves the state unwritten, so the next tick tries again
+        if let Some(path) = &self.path
+            && write_recovery_file(path, layers, palette, source.map(|s| s.as_str())).is_ok()
+        {
+            self.written_revision = Some(self.revision);
+            self.last_write = std::time::Instant::now();
+        }
+    }
+
+    // The document now matches a file on disk (saved, exported or opened)
+    fn saved(&mut self, history: &History, palette: &[Color]) {
+        self.seen_node = history.current;
+        self.seen_palette = palette.to_vec();
+        self.written_revision = Some(self.revision);
+        if let Ok(mut snapshot) = self.snapshot.lock() {
+            snapshot.unsaved = false;
+        }
+        self.discard();
+    }
+
+    fn discard(&self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    // On a panic the terminal is put back into cooked mode on the main screen before
+    // the message prints, and any unsaved canvas is dumped to the recovery file.
+    fn install_panic_hook(&self) {
+        let snapshot = Arc::clone(&self.snapshot);
+        let path = self.path.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = disable_raw_mode();
+            let _ = execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture, cursor::Show);
+            if let (Some(path), Ok(snapshot)) = (&path, snapshot.try_lock())
+                && snapshot.unsaved
+            {
+                match write_recovery_file(path, &snapshot.layers, &snapshot.palette, snapshot.source.as_deref()) {
+                    Ok(()) => eprintln!("raint: unsaved canvas written to {}", path.display()),
+                    Err(e) => eprintln!("raint: could not write recovery file: {}", e),
+                }
+            }
+            default_hook(info);
+        }));
+    }
+}
+
+// Offers abandoned recovery files one at a time, newest first. A declined file is
+// deleted and one kept for later is left alone; the first accepted one is returned
+// along with its palette and the path it was made from, and becomes this session's
+// recovery file so it survives until the next write.
+fn offer_recovery() -> Option<(LayerStack, Option<Vec<Color>>, Option<String>)> {
+    for (path, modified) in abandoned_recovery_files() {
+        let doc = match std::fs::read(&path).map_err(|e| e.to_string()).and_then(|data| decode_rai(&data).map_err(|e| e.to_string())) {
+            Ok(doc) => doc,
+            Err(e) => {
+                eprintln!("raint: skipping unreadable recovery file {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let source = doc.metadata.into_iter().find(|(key, _)| key == "source").map(|(_, value)| value);
+        let age = modified.elapsed().unwrap_or_default();
+        println!(
+            "Found unsaved work from a session that ended {} ago ({}, {}x{}).",
+            format_age(age),
+            source.as_deref().unwrap_or("untitled"),
+            doc.layers.width,
+            doc.layers.height
+        );
+        print!("Restore it? [Y/n/l, n deletes it, l keeps it for later] ");
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() {
+            return None;
+        }
+        let answer = answer.trim().to_lowercase();
+        if answer.starts_with('l') {
+            continue;
+        }
+        if answer.starts_with('n') {
+            let _ = std::fs::remove_file(&path);
+            continue;
+        }
+        // If the rename fails the file stays where it is and is offered again next time
+        if let Some(own) = recovery_path() {
+            let _ = std::fs::rename(&path, own);
+        }
+        return Some((doc.layers, doc.palette, source));
+    }
+    None
+}
+
+// .rai v2 layout (all integers little endian):
+//   magic[8] version:u16 pixel_format:u8 compression:u8 width:u32 height:u32 header_crc:u32
+//   then chunks of tag[4] length:u32 data[length] crc:u32, ending with an END chunk.
+// PIXL holds the flattened image; each LAYR chunk holds one layer, bottom first, as
+//   flags:u8 opacity:u8 name_len:u16 name[name_len] then RGBA pixels.
+// The optional PALT chunk holds the document palette as RGBA entries.
+// The optional HIST chunk holds the undo tree as raw_len:u32 then zlib data (see encode_history).
+// Files without the magic are legacy v1: width:u32 height:u32 followed by raw RGB triplets.
+// Version 2 files written before alpha support use pixel format RGB8 and load as opaque.
+const RAI_MAGIC: [u8; 8] = [0x89, b'R', b'A', b'I', b'\r', b'\n', 0x1a, b'\n'];
+const RAI_VERSION: u16 = 2;
+const RAI_HEADER_LEN: usize = 24;
+const RAI_PIXEL_RGB8: u8 = 0;
+const RAI_PIXEL_RGBA8: u8 = 1;
+const RAI_COMPRESSION_NONE: u8 = 0;
+const RAI_COMPRESSION_ZLIB: u8 = 1;
+const RAI_LAYER_VISIBLE: u8 = 1;
+const RAI_LAYER_LOCKED: u8 = 2;
+const RAI_LAYER_ACTIVE: u8 = 4;
+
+#[derive(Debug)]
+enum RaiError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnsupportedFormat(String),
+    TooLarge { width: usize, height: usize },
+    Truncated,
+    ChecksumMismatch(String),
+    Corrupt(String),
+}
+
+impl std::fmt::Display for RaiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RaiError::BadMagic => write!(f, "not a .rai file (bad magic bytes)"),
+            RaiError::UnsupportedVersion(v) => write!(f, ".rai format version {} is not supported", v),
+            RaiError::UnsupportedFormat(what) => write!(f, "unsupported .rai {}", what),
+            RaiError::TooLarge { width, height } => write!(
+                f,
+                "canvas size {}x{} is too large (limit {}x{})",
+                width, height, MAX_CANVAS_SIZE, MAX_CANVAS_SIZE
+            ),
+            RaiError::Truncated => write!(f, ".rai file is truncated"),
+            RaiError::ChecksumMismatch(part) => write!(f, ".rai checksum mismatch in {}", part),
+            RaiError::Corrupt(why) => write!(f, "corrupt .rai file: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for RaiError {}
+
+fn rai_chunk_crc(tag: &[u8], data: &[u8]) -> u32 {
+    let mut crc_input = tag.to_vec();
+    crc_input.extend_from_slice(data);
+    crc32(&crc_input)
+}
+
+fn write_rai_chunk(out: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(&rai_chunk_crc(tag, data).to_le_bytes());
+}
+
+fn encode_rai(
+    layers: &LayerStack,
+    metadata: &[(&str, &str)],
+    palette: Option<&[Color]>,
+    history: Option<&History>,
+) -> Vec<u8> {
+    let canvas = layers.composite();
+    let mut out = RAI_MAGIC.to_vec();
+    out.extend_from_slice(&RAI_VERSION.to_le_bytes());
+    out.push(RAI_PIXEL_RGBA8);
+    out.push(RAI_COMPRESSION_ZLIB);
+    out.extend_from_slice(&(canvas.width as u32).to_le_bytes());
+    out.extend_from_slice(&(canvas.height as u32).to_le_bytes());
+    let header_crc = crc32(&out);
+    out.extend_from_slice(&header_crc.to_le_bytes());
+
+    for (key, value) in metadata {
+        let mut entry = key.as_bytes().to_vec();
+        entry.push(0);
+        entry.extend_from_slice(value.as_bytes());
+        write_rai_chunk(&mut out, b"META", &entry);
+    }
+
+    let raw: Vec<u8> = canvas.pixels.iter().flat_map(|p| p.iter().copied()).collect();
+    write_rai_chunk(&mut out, b"PIXL", &zlib_compress(&raw));
+
+    for (i, layer) in layers.layers.iter().enumerate() {
+        let mut flags = 0;
+        if layer.visible {
+            flags |= RAI_LAYER_VISIBLE;
+        }
+        if layer.locked {
+            flags |= RAI_LAYER_LOCKED;
+        }
+        if i == layers.active {
+            flags |= RAI_LAYER_ACTIVE;
+        }
+        let name = layer.name.as_bytes();
+        let mut body = vec![flags, layer.opacity];
+        body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        body.extend_from_slice(name);
+
+        let rgba: Vec<u8> = layer.canvas.pixels.iter().flat_map(|p| p.iter().copied()).collect();
+        body.extend_from_slice(&zlib_compress(&rgba));
+        write_rai_chunk(&mut out, b"LAYR", &body);
+    }
+
+    if let Some(palette) = palette {
+        let raw: Vec<u8> = palette.iter().flat_map(|c| c.iter().copied()).collect();
+        write_rai_chunk(&mut out, b"PALT", &raw);
+    }
+
+    if let Some(history) = history {
+        let raw = encode_history(history);
+        let mut body = (raw.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(&zlib_compress(&raw));
+        write_rai_chunk(&mut out, b"HIST", &body);
+    }
+
+    write_rai_chunk(&mut out, b"END\0", &[]);
+    out
+}
+
+fn read_u32_le(data: &[u8], pos: usize) -> Result<u32, RaiError> {
+    let bytes = data.get(pos..pos + 4).ok_or(RaiError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn check_rai_dimensions(width: usize, height: usize) -> Result<(), RaiError> {
+    if width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+        return Err(RaiError::TooLarge { width, height });
+    }
+    if width == 0 || height == 0 {
+        return Err(RaiError::Corrupt(format!("canvas size {}x{} is empty", width, height)));
+    }
+    Ok(())
+}
+
+fn pixels_from_rgb(raw: &[u8]) -> Vec<Color> {
+    raw.chunks_exact(3).map(|c| [c[0], c[1], c[2], 255]).collect()
+}
+
+fn pixels_from_rgba(raw: &[u8]) -> Vec<Color> {
+    raw.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect()
+}
+
+fn decode_rai_layer(body: &[u8], width: usize, height: usize, compression: u8) -> Result<(Layer, bool), RaiError> {
+    if body.len() < 4 {
+        return Err(RaiError::Truncated);
+    }
+    let flags = body[0];
+    let opacity = body[1].min(100);
+    let name_len = u16::from_le_bytes([body[2], body[3]]) as usize;
+    let name = body.get(4..4 + name_len).ok_or(RaiError::Truncated)?;
+    let payload = &body[4 + name_len..];
+
+    let expected_len = width * height * 4;
+    let rgba = if compression == RAI_COMPRESSION_ZLIB {
+        zlib_decompress(payload, expected_len).map_err(RaiError::Corrupt)?
+    } else {
+        payload.to_vec()
+    };
+    if rgba.len() != expected_len {
+        return Err(RaiError::Truncated);
+    }
+
+    let canvas = Canvas { width, height, pixels: pixels_from_rgba(&rgba) };
+
+    let layer = Layer {
+        name: String::from_utf8_lossy(name).into_owned(),
+        visible: flags & RAI_LAYER_VISIBLE != 0,
+        opacity,
+        locked: flags & RAI_LAYER_LOCKED != 0,
+        canvas,
+    };
+    Ok((layer, flags & RAI_LAYER_ACTIVE != 0))
+}
+
+// Reads the chunk at `pos`, checking its CRC. Returns the tag, body and next position.
+fn read_rai_chunk(data: &[u8], pos: usize) -> Result<(&[u8], &[u8], usize), RaiError> {
+    let tag = data.get(pos..pos + 4).ok_or(RaiError::Truncated)?;
+    let len = read_u32_le(data, pos + 4)? as usize;
+    let body = data.get(pos + 8..pos + 8 + len).ok_or(RaiError::Truncated)?;
+    let stored_crc = read_u32_le(data, pos + 8 + len)?;
+    if rai_chunk_crc(tag, body) != stored_crc {
+        let name = String::from_utf8_lossy(tag).trim_end_matches('\0').to_string();
+        return Err(RaiError::ChecksumMismatch(format!("{} chunk", name)));
+    }
+    Ok((tag, body, pos + 12 + len))
+}
+
+// A copy of a v2 file without its HIST chunk, or None if it has none
+fn strip_rai_history(data: &[u8]) -> Result<Option<Vec<u8>>, RaiError> {
+    if !data.starts_with(&RAI_MAGIC) || data.len() < RAI_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut out = data[..RAI_HEADER_LEN].to_vec();
+    let mut pos = RAI_HEADER_LEN;
+    let mut found = false;
+    loop {
+        let (tag, _, next) = read_rai_chunk(data, pos)?;
+        if tag == b"HIST" {
+            found = true;
+        } else {
+            out.extend_from_slice(&data[pos..next]);
+        }
+        if tag == b"END\0" {
+            break;
+        }
+        pos = next;
+    }
+    Ok(found.then_some(out))
+}
+
+struct RaiDocument {
+    layers: LayerStack,
+    metadata: Vec<(String, String)>,
+    palette: Option<Vec<Color>>,
+    // Still-compressed HIST chunk body, decoded once the layers are known
+    history: Option<Vec<u8>>,
+}
+
+fn decode_rai(data: &[u8]) -> Result<RaiDocument, RaiError> {
+    if !data.starts_with(&RAI_MAGIC) {
+        return decode_rai_v1(data);
+    }
+    if data.len() < RAI_HEADER_LEN {
+        return Err(RaiError::Truncated);
+    }
+
+    let header_crc = read_u32_le(data, 20)?;
+    if crc32(&data[..20]) != header_crc {
+        return Err(RaiError::ChecksumMismatch("header".into()));
+    }
+
+    let version = u16::from_le_bytes([data[8], data[9]]);
+    if version != RAI_VERSION {
+        return Err(RaiError::UnsupportedVersion(version));
+    }
+    let pixel_format = data[10];
+    let bytes_per_pixel = match pixel_format {
+        RAI_PIXEL_RGB8 => 3,
+        RAI_PIXEL_RGBA8 => 4,
+        _ => return Err(RaiError::UnsupportedFormat(format!("pixel format {}", pixel_format))),
+    };
+    let compression = data[11];
+    if compression != RAI_COMPRESSION_NONE && compression != RAI_COMPRESSION_ZLIB {
+        return Err(RaiError::UnsupportedFormat(format!("compression method {}", compression)));
+    }
+
+    let width = read_u32_le(data, 12)? as usize;
+    let height = read_u32_le(data, 16)? as usize;
+    check_rai_dimensions(width, height)?;
+    let expected_len = width * height * bytes_per_pixel;
+
+    let mut pos = RAI_HEADER_LEN;
+    let mut metadata = Vec::new();
+    let mut pixels = None;
+    let mut layers = Vec::new();
+    let mut active = 0;
+    let mut history = None;
+    let mut palette = None;
+
+    loop {
+        let (tag, body, next) = read_rai_chunk(data, pos)?;
+        pos = next;
+
+        match tag {
+            b"META" => {
+                let split = body.iter().position(|&b| b == 0).unwrap_or(body.len());
+                let key = String::from_utf8_lossy(&body[..split]).into_owned();
+                let value = String::from_utf8_lossy(body.get(split + 1..).unwrap_or(&[])).into_owned();
+                metadata.push((key, value));
+            }
+            b"PIXL" => {
+                let raw = if compression == RAI_COMPRESSION_ZLIB {
+                    zlib_decompress(body, expected_len).map_err(RaiError::Corrupt)?
+                } else {
+                    body.to_vec()
+                };
+                if raw.len() != expected_len {
+                    return Err(RaiError::Truncated);
+                }
+                pixels = Some(if bytes_per_pixel == 4 { pixels_from_rgba(&raw) } else { pixels_from_rgb(&raw) });
+            }
+            b"LAYR" => {
+                let (layer, is_active) = decode_rai_layer(body, width, height, compression)?;
+                if is_active {
+                    active = layers.len();
+                }
+                layers.push(layer);
+            }
+            b"PALT" => {
+                if body.is_empty() || body.len() % 4 != 0 {
+                    return Err(RaiError::Corrupt("palette is not a list of RGBA colours".into()));
+                }
+                palette = Some(pixels_from_rgba(body));
+            }
+            b"HIST" => history = Some(body.to_vec()),
+            b"END\0" => break,
+            // Unknown chunks are skipped so newer writers stay readable
+            _ => {}
+        }
+    }
+
+    let layers = if layers.is_empty() {
+        let pixels = pixels.ok_or_else(|| RaiError::Corrupt("missing pixel data".into()))?;
+        LayerStack::from_canvas(Canvas { width, height, pixels })
+    } else {
+        LayerStack::from_layers(width, height, layers, active)
+    };
+    Ok(RaiDocument { layers, metadata, palette, history })
+}
+
+fn decode_rai_v1(data: &[u8]) -> Result<RaiDocument, RaiError> {
+    if data.len() < 8 {
+        return Err(RaiError::BadMagic);
+    }
+    let width = read_u32_le(data, 0)? as usize;
+    let height = read_u32_le(data, 4)? as usize;
+
+    // Legacy files have no signature, so anything whose header doesn't describe
+    // a sane canvas is treated as not being a .rai at all
+    if width == 0 || height == 0 {
+        return Err(RaiError::BadMagic);
+    }
+    check_rai_dimensions(width, height)?;
+
+    let expected_len = 8 + width * height * 3;
+    if data.len() < expected_len {
+        return Err(RaiError::Truncated);
+    }
+    if data.len() > expected_len {
+        return Err(RaiError::BadMagic);
+    }
+
+    let pixels = pixels_from_rgb(&data[8..]);
+    let layers = LayerStack::from_canvas(Canvas { width, height, pixels });
+    Ok(RaiDocument { layers, metadata: Vec::new(), palette: None, history: None })
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+const CRC_TABLE: [u32; 256] = make_crc_table();
+
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xffff_ffff
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitWriter {
+    out: Vec<u8>,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { out: Vec::new(), bit_buf: 0, bit_count: 0 }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bit_buf |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    // Huffman codes are stored most significant bit first, unlike everything else
+    fn write_code(&mut self, code: u32, length: u32) {
+        let reversed = code.reverse_bits() >> (32 - length);
+        self.write_bits(reversed, length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.out.push(self.bit_buf as u8);
+        }
+        self.out
+    }
+}
+
+fn write_fixed_literal(writer: &mut BitWriter, symbol: u16) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol as u32, 8),
+        144..=255 => writer.write_code(0x190 + (symbol - 144) as u32, 9),
+        256..=279 => writer.write_code((symbol - 256) as u32, 7),
+        _ => writer.write_code(0xc0 + (symbol - 280) as u32, 8),
+    }
+}
+
+fn write_fixed_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let len_index = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+    write_fixed_literal(writer, 257 + len_index as u16);
+    writer.write_bits((length - LENGTH_BASE[len_index] as usize) as u32, LENGTH_EXTRA[len_index] as u32);
+
+    let dist_index = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+    writer.write_code(dist_index as u32, 5);
+    writer.write_bits((distance - DIST_BASE[dist_index] as usize) as u32, DIST_EXTRA[dist_index] as u32);
+}
+
+// Single fixed-Huffman block with a hash-chain LZ77 matcher. Canvases are
+// mostly flat colour so this already shrinks them a lot.
+fn deflate(data: &[u8]) -> Vec<u8> {
+    const WINDOW: usize = 32768;
+    const HASH_SIZE: usize = 1 << 15;
+    const MAX_CHAIN: usize = 64;
+    const MIN_MATCH: usize = 3;
+    const MAX_MATCH: usize = 258;
+
+    let hash_at = |i: usize| -> usize {
+        let v = (data[i] as usize) << 16 | (data[i + 1] as usize) << 8 | data[i + 2] as usize;
+        (v.wrapping_mul(2654435761) >> 8) & (HASH_SIZE - 1)
+    };
+
+    let mut writer = BitWriter::new();
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let mut i = 0;
+
+    while i < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if i + MIN_MATCH <= data.len() {
+            let h = hash_at(i);
+            let mut candidate = head[h];
+            let mut chain = 0;
+            let max_len = MAX_MATCH.min(data.len() - i);
+
+            while candidate != usize::MAX && i - candidate <= WINDOW && chain < MAX_CHAIN {
+                let mut len = 0;
+                while len < max_len && data[candidate + len] == data[i + len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        let advance = if best_len >= MIN_MATCH {
+            write_fixed_match(&mut writer, best_len, best_dist);
+            best_len
+        } else {
+            write_fixed_literal(&mut writer, data[i] as u16);
+            1
+        };
+
+        for pos in i..i + advance {
+            if pos + MIN_MATCH <= data.len() {
+                let h = hash_at(pos);
+                prev[pos % WINDOW] = head[h];
+                head[h] = pos;
+            }
+        }
+        i += advance;
+    }
+
+    write_fixed_literal(&mut writer, 256);
+    writer.finish()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, bit_buf: 0, bit_count: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.pos).ok_or("compressed data ends unexpectedly")?;
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u64 << count) - 1) as u32;
+        self.bit_buf >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn from_lengths(lengths: &[u8]) -> Result<Self, String> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err("invalid Huffman code lengths".into());
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code in compressed data".into())
+    }
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    lit: &Huffman,
+    dist: &Huffman,
+    max_out: usize,
+) -> Result<(), String> {
+    loop {
+        let symbol = lit.decode(reader)?;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let idx = (symbol - 257) as usize;
+                let length = LENGTH_BASE[idx] as usize + reader.bits(LENGTH_EXTRA[idx] as u32)? as usize;
+                let dsym = dist.decode(reader)? as usize;
+                if dsym >= 30 {
+                    return Err("invalid distance code in compressed data".into());
+                }
+                let distance = DIST_BASE[dsym] as usize + reader.bits(DIST_EXTRA[dsym] as u32)? as usize;
+                if distance > out.len() {
+                    return Err("compressed data refers before start of output".into());
+                }
+                let start = out.len() - distance;
+                for k in 0..length {
+                    out.push(out[start + k]);
+                }
+            }
+            _ => return Err("invalid literal/length code in compressed data".into()),
+        }
+        if out.len() > max_out {
+            return Err("decompressed data is larger than expected".into());
+        }
+    }
+}
+
+fn inflate(data: &[u8], max_out: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader::new(data);
+    let mut out = Vec::new();
+
+    loop {
+        let is_final = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let header = data
+                    .get(reader.pos..reader.pos + 4)
+                    .ok_or("compressed data ends unexpectedly")?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let nlen = u16::from_le_bytes([header[2], header[3]]) as usize;
+                if len != !nlen & 0xffff {
+                    return Err("corrupt stored block length".into());
+                }
+                reader.pos += 4;
+                let stored = data
+                    .get(reader.pos..reader.pos + len)
+                    .ok_or("compressed data ends unexpectedly")?;
+                out.extend_from_slice(stored);
+                reader.pos += len;
+                if out.len() > max_out {
+                    return Err("decompressed data is larger than expected".into());
+                }
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let lit = Huffman::from_lengths(&lengths)?;
+                let dist = Huffman::from_lengths(&[5u8; 30])?;
+                inflate_block(&mut reader, &mut out, &lit, &dist, max_out)?;
+            }
+            2 => {
+                let hlit = reader.bits(5)? as usize + 257;
+                let hdist = reader.bits(5)? as usize + 1;
+                let hclen = reader.bits(4)? as usize + 4;
+
+                let mut code_lengths = [0u8; 19];
+                for &slot in CODE_LENGTH_ORDER.iter().take(hclen) {
+                    code_lengths[slot] = reader.bits(3)? as u8;
+                }
+                let code_huffman = Huffman::from_lengths(&code_lengths)?;
+
+                let mut lengths = vec![0u8; hlit + hdist];
+                let mut i = 0;
+                while i < lengths.len() {
+                    let symbol = code_huffman.decode(&mut reader)?;
+                    let (value, repeat) = match symbol {
+                        0..=15 => (symbol as u8, 1),
+                        16 => {
+                            if i == 0 {
+                                return Err("repeat code with no previous length".into());
+                            }
+                            (lengths[i - 1], 3 + reader.bits(2)? as usize)
+                        }
+                        17 => (0, 3 + reader.bits(3)? as usize),
+                        _ => (0, 11 + reader.bits(7)? as usize),
+                    };
+                    if i + repeat > lengths.len() {
+                        return Err("too many code lengths in dynamic block".into());
+                    }
+                    lengths[i..i + repeat].fill(value);
+                    i += repeat;
+                }
+
+                let lit = Huffman::from_lengths(&lengths[..hlit])?;
+                let dist = Huffman::from_lengths(&lengths[hlit..])?;
+                inflate_block(&mut reader, &mut out, &lit, &dist, max_out)?;
+            }
+            _ => return Err("invalid compressed block type".into()),
+        }
+
+        if is_final {
+            return Ok(out);
+        }
+    }
+}
+
+fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x9c];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn zlib_decompress(data: &[u8], max_out: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 6 {
+        return Err("zlib stream is too short".into());
+    }
+    let cmf = data[0];
+    let flg = data[1];
+    if cmf & 0x0f != 8 || !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+        return Err("invalid zlib header".into());
+    }
+    if flg & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported".into());
+    }
+
+    let out = inflate(&data[2..], max_out)?;
+    let trailer = &data[data.len() - 4..];
+    let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    if adler32(&out) != expected {
+        return Err("zlib checksum mismatch".into());
+    }
+    Ok(out)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn filter_row(filter: u8, row: &[u8], prev: &[u8], bpp: usize, out: &mut Vec<u8>) {
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predicted = match filter {
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            4 => paeth(a, b, c),
+            _ => 0,
+        };
+        out.push(row[i].wrapping_sub(predicted));
+    }
+}
+
+fn unfilter_row(filter: u8, row: &mut [u8], prev: &[u8], bpp: usize) -> Result<(), String> {
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            4 => paeth(a, b, c),
+            _ => return Err(format!("invalid PNG filter type {}", filter)),
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+fn encode_png(canvas: &Canvas) -> Vec<u8> {
+    // Fully opaque images are written as plain RGB to keep files small
+    let has_alpha = canvas.pixels.iter().any(|p| p[3] != 255);
+    let bpp = if has_alpha { 4 } else { 3 };
+    let row_len = canvas.width * bpp;
+    let mut raw = Vec::with_capacity((row_len + 1) * canvas.height);
+    let mut prev = vec![0u8; row_len];
+    let mut candidate = Vec::with_capacity(row_len);
+
+    for y in 0..canvas.height {
+        let row: Vec<u8> = canvas.pixels[y * canvas.width..(y + 1) * canvas.width]
+            .iter()
+            .flat_map(|p| p[..bpp].iter().copied())
+            .collect();
+
+        // Pick the filter with the smallest sum of signed residuals, as libpng does
+        let mut best_filter = 0;
+        let mut best_score = u64::MAX;
+        for filter in 0..5u8 {
+            candidate.clear();
+            filter_row(filter, &row, &prev, bpp, &mut candidate);
+            let score: u64 = candidate.iter().map(|&v| (v as i8).unsigned_abs() as u64).sum();
+            if score < best_score {
+                best_score = score;
+                best_filter = filter;
+            }
+        }
+
+        raw.push(best_filter);
+        filter_row(best_filter, &row, &prev, bpp, &mut raw);
+        prev = row;
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(canvas.width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(canvas.height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, if has_alpha { 6 } else { 2 }, 0, 0, 0]);
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    write_png_chunk(&mut out, b"IHDR", &ihdr);
+    write_png_chunk(&mut out, b"IDAT", &zlib_compress(&raw));
+    write_png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn decode_png(data: &[u8]) -> Result<Canvas, Box<dyn std::error::Error>> {
+    if data.len() < 8 || data[..8] != PNG_SIGNATURE {
+        return Err("not a PNG file (bad signature)".into());
+    }
+
+    let mut pos = 8;
+    let mut header: Option<(usize, usize, u8, u8, u8)> = None;
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut transparency: Vec<u8> = Vec::new();
+    let mut idat = Vec::new();
+    let mut seen_end = false;
+
+    while pos + 12 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        if pos + 12 + len > data.len() {
+            return Err("PNG chunk runs past end of file".into());
+        }
+        let kind = &data[pos + 4..pos + 8];
+        let body = &data[pos + 8..pos + 8 + len];
+        let stored_crc = u32::from_be_bytes([
+            data[pos + 8 + len],
+            data[pos + 9 + len],
+            data[pos + 10 + len],
+            data[pos + 11 + len],
+        ]);
+        if crc32(&data[pos + 4..pos + 8 + len]) != stored_crc {
+            return Err(format!("PNG chunk {} has a bad CRC", String::from_utf8_lossy(kind)).into());
+        }
+        pos += 12 + len;
+
+        match kind {
+            b"IHDR" => {
+                if body.len() != 13 {
+                    return Err("PNG IHDR chunk has the wrong size".into());
+                }
+                let width = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+                let height = u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize;
+                if body[10] != 0 || body[11] != 0 {
+                    return Err("PNG uses an unknown compression or filter method".into());
+                }
+                header = Some((width, height, body[8], body[9], body[12]));
+            }
+            b"PLTE" => {
+                palette = body.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+            }
+            b"tRNS" => transparency = body.to_vec(),
+            b"IDAT" => idat.extend_from_slice(body),
+            b"IEND" => {
+                seen_end = true;
+                break;
+            }
+            _ => {
+                if kind[0] & 0x20 == 0 {
+                    return Err(format!("PNG has unsupported critical chunk {}", String::from_utf8_lossy(kind)).into());
+                }
+            }
+        }
+    }
+
+    let (width, height, depth, color_type, interlace) = header.ok_or("PNG is missing its IHDR chunk")?;
+    if !seen_end {
+        return Err("PNG is truncated (no IEND chunk)".into());
+    }
+    if width == 0 || height == 0 {
+        return Err("PNG has zero width or height".into());
+    }
+    if width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+        return Err(format!(
+            "PNG is {}x{} pixels, larger than the {}x{} canvas limit",
+            width, height, MAX_CANVAS_SIZE, MAX_CANVAS_SIZE
+        )
+        .into());
+    }
+
+    let channels = match (color_type, depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => 1,
+        (2, 8 | 16) => 3,
+        (3, 1 | 2 | 4 | 8) => 1,
+        (4, 8 | 16) => 2,
+        (6, 8 | 16) => 4,
+        _ => return Err(format!("unsupported PNG colour type {} with bit depth {}", color_type, depth).into()),
+    };
+    if color_type == 3 && palette.is_empty() {
+        return Err("indexed PNG is missing its PLTE chunk".into());
+    }
+    if interlace > 1 {
+        return Err(format!("unknown PNG interlace method {}", interlace).into());
+    }
+
+    let bits_per_pixel = channels * depth as usize;
+    let bpp = bits_per_pixel.div_ceil(8);
+    let passes: &[(usize, usize, usize, usize)] = if interlace == 1 {
+        &[(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)]
+    } else {
+        &[(0, 0, 1, 1)]
+    };
+
+    let pass_size = |x0: usize, y0: usize, dx: usize, dy: usize| {
+        let pw = if width > x0 { (width - x0).div_ceil(dx) } else { 0 };
+        let ph = if height > y0 { (height - y0).div_ceil(dy) } else { 0 };
+        (pw, ph)
+    };
+    let expected: usize = passes
+        .iter()
+        .map(|&(x0, y0, dx, dy)| {
+            let (pw, ph) = pass_size(x0, y0, dx, dy);
+            if pw == 0 { 0 } else { ((pw * bits_per_pixel).div_ceil(8) + 1) * ph }
+        })
+        .sum();
+
+    let raw = zlib_decompress(&idat, expected)?;
+    if raw.len() < expected {
+        return Err("PNG image data is truncated".into());
+    }
+
+    let sample = |row: &[u8], index: usize| -> u16 {
+        match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth as usize;
+                let byte = row[bit / 8];
+                let shift = 8 - depth as usize - bit % 8;
+                ((byte >> shift) & ((1u8 << depth) - 1)) as u16
+            }
+        }
+    };
+    let to_u8 = |value: u16| -> u8 {
+        match depth {
+            16 => (value >> 8) as u8,
+            8 => value as u8,
+            _ => (value as u32 * 255 / ((1u32 << depth) - 1)) as u8,
+        }
+    };
+    let key = |i: usize| -> Option<u16> {
+        transparency.get(i * 2..i * 2 + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+
+    let mut canvas = Canvas::new(width, height);
+    let mut offset = 0;
+
+    for &(x0, y0, dx, dy) in passes {
+        let (pw, ph) = pass_size(x0, y0, dx, dy);
+        if pw == 0 || ph == 0 {
+            continue;
+        }
+        let row_len = (pw * bits_per_pixel).div_ceil(8);
+        let mut prev = vec![0u8; row_len];
+
+        for py in 0..ph {
+            let filter = raw[offset];
+            let mut row = raw[offset + 1..offset + 1 + row_len].to_vec();
+            offset += row_len + 1;
+            unfilter_row(filter, &mut row, &prev, bpp)?;
+
+            for px in 0..pw {
+                let base = px * channels;
+                let (r, g, b, a) = match color_type {
+                    0 => {
+                        let v = sample(&row, base);
+                        let a = if key(0) == Some(v) { 0 } else { 255 };
+                        let v = to_u8(v);
+                        (v, v, v, a)
+                    }
+                    2 => {
+                        let (r, g, b) = (sample(&row, base), sample(&row, base + 1), sample(&row, base + 2));
+                        let a = if (key(0), key(1), key(2)) == (Some(r), Some(g), Some(b)) { 0 } else { 255 };
+                        (to_u8(r), to_u8(g), to_u8(b), a)
+                    }
+                    3 => {
+                        let index = sample(&row, base) as usize;
+                        let [r, g, b] = *palette
+                            .get(index)
+                            .ok_or_else(|| format!("PNG palette index {} out of range", index))?;
+                        (r, g, b, transparency.get(index).copied().unwrap_or(255))
+                    }
+                    4 => {
+                        let v = to_u8(sample(&row, base));
+                        (v, v, v, to_u8(sample(&row, base + 1)))
+                    }
+                    _ => (
+                        to_u8(sample(&row, base)),
+                        to_u8(sample(&row, base + 1)),
+                        to_u8(sample(&row, base + 2)),
+                        to_u8(sample(&row, base + 3)),
+                    ),
+                };
+
+                canvas.set_pixel(x0 + px * dx, y0 + py * dy, [r, g, b, a]);
+            }
+            prev = row;
+        }
+    }
+
+    Ok(canvas)
+}
+
+// Headless drawing for scripts and CI: `raint --batch script.txt -o out.rai` runs one
+// command per line against a canvas. Blank lines and lines starting with # are skipped.
+//   size W [H]               new white canvas
+//   load PATH / save PATH    .rai or .png; layered files are flattened on load
+//   color R G B [A]          thickness N
+//   line X0 Y0 X1 Y1         circle CX CY R
+//   rect CX CY HALF [HALF_Y] fill X Y
+#[derive(Debug)]
+struct BatchError {
+    line: usize,
+    message: String,
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+struct BatchState {
+    canvas: Option<Canvas>,
+    color: Color,
+    thickness: usize,
+}
+
+fn batch_numbers<T: std::str::FromStr>(command: &str, args: &[&str], min: usize, max: usize) -> Result<Vec<T>, String> {
+    if args.len() < min || args.len() > max {
+        let expected = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+        return Err(format!("'{}' takes {} arguments, got {}", command, expected, args.len()));
+    }
+    args.iter()
+        .map(|arg| arg.parse::<T>().map_err(|_| format!("'{}': invalid number '{}'", command, arg)))
+        .collect()
+}
+
+// Coordinates and sizes further than this from the origin are rejected, which keeps
+// the drawing maths well inside i32 however large the canvas is
+const BATCH_COORD_LIMIT: i32 = 2 * MAX_CANVAS_SIZE as i32;
+
+// Parses drawing arguments; the first `points` are coordinates, the rest are sizes
+fn batch_coordinates(command: &str, args: &[&str], min: usize, max: usize, points: usize) -> Result<Vec<i32>, String> {
+    let n = batch_numbers::<i32>(command, args, min, max)?;
+    for (i, &v) in n.iter().enumerate() {
+        if i < points && v.abs() > BATCH_COORD_LIMIT {
+            return Err(format!("'{}': coordinate {} is outside -{}-{}", command, v, BATCH_COORD_LIMIT, BATCH_COORD_LIMIT));
+        }
+        if i >= points && !(0..=BATCH_COORD_LIMIT).contains(&v) {
+            return Err(format!("'{}': size {} is outside 0-{}", command, v, BATCH_COORD_LIMIT));
+        }
+    }
+    Ok(n)
+}
+
+fn run_batch_command(state: &mut BatchState, command: &str, rest: &str) -> Result<(), String> {
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    match command {
+        "size" => {
+            let n = batch_numbers::<usize>(command, &args, 1, 2)?;
+            let (width, height) = (n[0], *n.get(1).unwrap_or(&n[0]));
+            if !(2..=MAX_CANVAS_SIZE).contains(&width) || !(2..=MAX_CANVAS_SIZE).contains(&height) {
+                return Err(format!("canvas size {}x{} is outside 2-{}", width, height, MAX_CANVAS_SIZE));
+            }
+            state.canvas = Some(Canvas::new(width, height));
+        }
+        "color" => {
+            let c = batch_numbers::<u8>(command, &args, 3, 4)?;
+            state.color = [c[0], c[1], c[2], c.get(3).copied().unwrap_or(255)];
+        }
+        "thickness" => {
+            let t = batch_numbers::<usize>(command, &args, 1, 1)?[0];
+            if !(1..=10).contains(&t) {
+                return Err(format!("thickness {} is outside 1-10", t));
+            }
+            state.thickness = t;
+        }
+        "load" | "save" if rest.is_empty() => return Err(format!("'{}' needs a file path", command)),
+        "load" => {
+            let layers = load_image(rest).map_err(|e| format!("cannot load '{}': {}", rest, e))?;
+            state.canvas = Some(layers.composite());
+        }
+        "save" => {
+            let canvas = state.canvas.as_ref().ok_or("nothing to save yet; start with 'size' or 'load'")?;
+            let filepath = with_default_extension(rest);
+            save_image(&LayerStack::from_canvas(canvas.clone()), &filepath)
+                .map_err(|e| format!("cannot save '{}': {}", filepath, e))?;
+        }
+        "line" | "circle" | "rect" | "fill" => {
+            let (color, thickness) = (state.color, state.thickness);
+            let canvas = state.canvas.as_mut().ok_or("no canvas yet; start with 'size' or 'load'")?;
+            match command {
+                "line" => {
+                    let n = batch_coordinates(command, &args, 4, 4, 4)?;
+                    draw_line_with_brush(canvas, n[0], n[1], n[2], n[3], thickness, color);
+                }
+                "circle" => {
+                    let n = batch_coordinates(command, &args, 3, 3, 2)?;
+                    draw_circle(canvas, n[0], n[1], n[2], color);
+                }
+                "rect" => {
+                    let n = batch_coordinates(command, &args, 3, 4, 2)?;
+                    match n.get(3) {
+                        Some(&hy) => draw_rect_preview(canvas, n[0], n[1], n[2], hy, color),
+                        None => draw_rectangle(canvas, n[0], n[1], n[2], color),
+                    }
+                }
+                _ => {
+                    let n = batch_coordinates(command, &args, 2, 2, 2)?;
+                    flood_fill(canvas, n[0], n[1], color, None, FillOptions::default());
+                }
+            }
+        }
+        _ => return Err(format!("unknown command '{}'", command)),
+    }
+    Ok(())
+}
+
+fn run_batch(script: &str, initial: Option<Canvas>) -> Result<Option<Canvas>, BatchError> {
+    let mut state = BatchState { canvas: initial, color: [0, 0, 0, 255], thickness: 1 };
+
+    for (index, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        run_batch_command(&mut state, &command.to_lowercase(), rest.trim())
+            .map_err(|message| BatchError { line: index + 1, message })?;
+    }
+
+    Ok(state.canvas)
+}
+
+fn run_batch_file(script_path: &str, options: &CliOptions) -> Result<(), String> {
+    let script = std::fs::read_to_string(expand_path(script_path))
+        .map_err(|e| format!("cannot read '{}': {}", script_path, e))?;
+    let initial = options.size.map(|(width, height)| Canvas::new(width, height));
+    let canvas = run_batch(&script, initial).map_err(|e| format!("{}: {}", script_path, e))?;
+
+    if let Some(output) = &options.output {
+        let canvas = canvas.ok_or(format!("{}: script never created a canvas", script_path))?;
+        let filepath = with_default_extension(output);
+        save_image(&LayerStack::from_canvas(canvas), &filepath).map_err(|e| format!("cannot save '{}': {}", filepath, e))?;
+    }
+    Ok(())
+}
+
+// Command line: `raint [OPTIONS] [FILE]`. FILE is opened if it exists, otherwise it
+// becomes the save target for a new canvas.
+struct CliOptions {
+    path: Option<String>,
+    size: Option<(usize, usize)>,
+    output: Option<String>,
+    batch: Option<String>,
+    undo_steps: usize,
+    undo_megabytes: usize,
+    save_history: Option<HistoryStorage>,
+    palette: Option<String>,
+}
+
+enum CliCommand {
+    Run(CliOptions),
+    StripHistory(String),
+    Help,
+    Version,
+}
+
+fn usage() -> String {
+    format!(
+        "Usage: raint [OPTIONS] [FILE]\n       \
+         raint --batch SCRIPT -o OUTPUT\n\
+         \n\
+         Opens FILE (.rai or .png) if it exists, otherwise starts a new canvas saved to FILE.\n\
+         \n\
+         Options:\n  \
+           -s, --size WxH            Size of a new canvas ({min}-{max} pixels per side, e.g. 64x32 or 64)\n  \
+           -o, --output PATH         Default save target for * (defaults to FILE), or the batch result\n  \
+           -b, --batch SCRIPT        Run a drawing script without the terminal UI (see README)\n  \
+           -p, --palette FILE        Start with the palette from a .gpl, .pal or .hex file\n      \
+           --undo-steps N        Undo history length (default {steps})\n      \
+           --undo-memory MB      Memory budget for undo history (default {megabytes} MB)\n      \
+           --save-history MODE   Save undo history: embed (in .rai), sidecar (FILE.history) or off\n      \
+           --strip-history FILE  Remove saved undo history from FILE and exit\n  \
+           -h, --help                Print this help and exit\n  \
+           -V, --version             Print the version and exit",
+        min = 2,
+        max = MAX_CANVAS_SIZE,
+        steps = DEFAULT_UNDO_STEPS,
+        megabytes = DEFAULT_UNDO_MEGABYTES,
+    )
+}
+
+fn parse_size(value: &str) -> Result<(usize, usize), String> {
+    let (w, h) = match value.split_once(['x', 'X']) {
+        Some((w, h)) => (w, h),
+        None => (value, value),
+    };
+    match (w.trim().parse::<usize>(), h.trim().parse::<usize>()) {
+        (Ok(w), Ok(h)) if (2..=MAX_CANVAS_SIZE).contains(&w) && (2..=MAX_CANVAS_SIZE).contains(&h) => Ok((w, h)),
+        (Ok(_), Ok(_)) => Err(format!("canvas size '{}' is outside 2-{}", value, MAX_CANVAS_SIZE)),
+        _ => Err(format!("invalid canvas size '{}', expected WxH", value)),
+    }
+}
+
+fn parse_limit(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{} needs a positive number, got '{}'", name, value)),
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliCommand, String> {
+    let mut options = CliOptions {
+        path: None,
+        size: None,
+        output: None,
+        batch: None,
+        undo_steps: DEFAULT_UNDO_STEPS,
+        undo_megabytes: DEFAULT_UNDO_MEGABYTES,
+        save_history: None,
+        palette: None,
+    };
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| inline_value.clone().or_else(|| args.next()).ok_or(format!("{} needs a value", name));
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(CliCommand::Help),
+            "-V" | "--version" => return Ok(CliCommand::Version),
+            "-s" | "--size" => options.size = Some(parse_size(&value("--size")?)?),
+            "-o" | "--output" => options.output = Some(value("--output")?),
+            "-b" | "--batch" => options.batch = Some(value("--batch")?),
+            "--undo-steps" => options.undo_steps = parse_limit("--undo-steps", &value("--undo-steps")?)?,
+            "--undo-memory" => options.undo_megabytes = parse_limit("--undo-memory", &value("--undo-memory")?)?,
+            "--save-history" => {
+                options.save_history = Some(match value("--save-history")?.as_str() {
+                    "embed" => HistoryStorage::Embedded,
+                    "sidecar" => HistoryStorage::Sidecar,
+                    "off" => HistoryStorage::Off,
+                    other => return Err(format!("--save-history must be embed, sidecar or off, got '{}'", other)),
+                })
+            }
+            "-p" | "--palette" => options.palette = Some(value("--palette")?),
+            "--strip-history" => return Ok(CliCommand::StripHistory(value("--strip-history")?)),
+            _ if arg.starts_with('-') && arg.len() > 1 => return Err(format!("unknown option '{}'", arg)),
+            _ if options.path.is_some() => return Err(format!("unexpected argument '{}'", arg)),
+            _ => options.path = Some(arg),
+        }
+    }
+
+    if options.batch.is_some() && options.path.is_some() {
+        return Err("--batch does not take a FILE; use 'load' in the script".to_string());
+    }
+    Ok(CliCommand::Run(options))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(CliCommand::Run(options)) => options,
+        Ok(CliCommand::Help) => {
+            println!("{}", usage());
+            return Ok(());
+        }
+        Ok(CliCommand::Version) => {
+            println!("raint {}", RAINT_VERSION);
+            return Ok(());
+        }
+        Ok(CliCommand::StripHistory(path)) => {
+            match strip_history(&path) {
+                Ok(true) => println!("Removed undo history from {}", path),
+                Ok(false) => println!("{} has no saved undo history", path),
+                Err(e) => {
+                    eprintln!("raint: error stripping '{}': {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("raint: {}\n\n{}", e, usage());
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(script) = &options.batch {
+        if let Err(e) = run_batch_file(script, &options) {
+            eprintln!("raint: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let existing = options.path.as_ref().filter(|path| std::path::Path::new(&expand_path(path)).exists());
+    let mut save_target = options.output.clone().or(options.path.clone());
+    let undo_budget = options.undo_megabytes * 1024 * 1024;
+    let mut saved_history = None;
+    let mut saved_palette = None;
+    // A file or size on the command line wins; recovery files wait for a plain launch
+    let recovered = if options.path.is_none() && options.size.is_none() { offer_recovery() } else { None };
+    let restoring = recovered.is_some();
+
+    let mut layers = if let Some((recovered, palette, source)) = recovered {
+        save_target = source.or(save_target);
+        saved_palette = palette;
+        recovered
+    } else if let Some(path) = existing {
+        if options.size.is_some() {
+            eprintln!("raint: --size only applies to new canvases, but '{}' already exists\n\n{}", path, usage());
+            std::process::exit(2);
+        }
+        match load_document(path) {
+            Ok(doc) => {
+                saved_history = doc.history.map(|body| (body, doc.storage));
+                saved_palette = doc.palette;
+                doc.layers
+            }
+            Err(e) => {
+                eprintln!("raint: error loading '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some((width, height)) = options.size {
+        LayerStack::new(width, height)
+    } else {
+        std::process::Command::new("clear").status()?;
+
+        println!("\n╔════════════════════════════════════════╗");
+        println!("║      Raint - v.{}                  ║", RAINT_VERSION);
+        println!("╚════════════════════════════════════════╝\n");
+        println!("Enter canvas size (width height) or single number for square.");
+        println!("Range: 2-{} pixels\nExamples: '40' or '256 128'\n", MAX_CANVAS_SIZE);
+
+        let (width, height) = loop {
+            print!("Size> ");
+            let _ = io::stdout().flush();
+
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input).is_err() {
+                return Ok(());
+            }
+
+            let parts: Vec<&str> = input.split_whitespace().collect();
+
+            if parts.is_empty() {
+                continue;
+            }
+
+            if parts.len() == 1 {
+                if let Ok(n) = parts[0].parse::<usize>() {
+                    let n = clamp(n, 2, MAX_CANVAS_SIZE);
+                    break (n, n);
+                }
+            } else if let (Ok(w), Ok(h)) = (parts[0].parse::<usize>(), parts[1].parse::<usize>()) {
+                break (clamp(w, 2, MAX_CANVAS_SIZE), clamp(h, 2, MAX_CANVAS_SIZE));
+            }
+
+            println!("Invalid input. Try again.");
+        };
+
+        LayerStack::new(width, height)
+    };
+
+    let mut history = History::new(&layers, options.undo_steps, undo_budget);
+    let mut history_storage = options.save_history.unwrap_or(HistoryStorage::Off);
+    if let Some((body, storage)) = saved_history {
+        match decode_history(&body, &layers, options.undo_steps, undo_budget) {
+            Ok(restored) => {
+                history = restored;
+                history_storage = options.save_history.unwrap_or(storage);
+            }
+            Err(e) => eprintln!("raint: ignoring saved undo history: {}", e),
+        }
+    }
+    let mut current_color: Color = [0, 0, 0, 255];
+    let mut secondary_color: Color = WHITE;
+    if let Some(path) = &options.palette {
+        match load_palette(path) {
+            Ok(colors) => saved_palette = Some(colors),
+            Err(e) => {
+                eprintln!("raint: error loading palette '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut palette = saved_palette.map(Palette::from_colors).unwrap_or_else(Palette::new);
+    let mut mouse_captured = false;
+    let mut status_message: Option<String> = None;
+    let mut recent_colors: VecDeque<Color> = VecDeque::new();
+    let mut sample_composite = true;
+    let mut fill_options = FillOptions::default();
+    let mut shape_options = ShapeOptions::default();
+    let mut line_options = LineOptions::default();
+    let mut soft_brush = false;
+    let mut brush_thickness: usize = 1;
+    let mut viewport = Viewport::new();
+    let mut selection: Option<Selection> = None;
+    let mut clipboard: Option<Canvas> = None;
+    let mut autosave = Autosave::new(&layers, &history, &palette.colors, save_target.as_ref(), restoring);
+    autosave.install_panic_hook();
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+
+    let backend = CrosstermBackend::new(io::stdout());
+    let mut terminal = Terminal::new(backend)?;
+    terminal.clear()?;
+
+    'main_loop: loop {
+        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+        // Help, eyedropper, lock and line tool; tools set their own keys on entry
+        viewport.tool_keys = &[KeyCode::Char('h'), KeyCode::Char('j'), KeyCode::Char('k'), KeyCode::Char('l')];
+        // Colours picked anywhere (prompt, palette, picker) become recent once back here
+        if recent_colors.front() != Some(&current_color) {
+            remember_color(&mut recent_colors, current_color);
+        }
+        terminal.draw(|f| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(0)
+                .constraints([Constraint::Min(1), Constraint::Length(2)])
+                .split(f.size());
+
+            let areas = split_canvas_area(chunks[0], &palette);
+            viewport.area = areas[0];
+            let canvas_spans = viewport.render(&layers, selection.as_ref());
+            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+            f.render_widget(canvas_widget, areas[0]);
+            f.render_widget(layer_panel(&layers), areas[1]);
+            palette.area = areas[2];
+            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+            let info_text = format!(
+                "H - Help | Color: RGBA({}, {}, {}, {}) | Thickness: {} | Layer: {} | Zoom: {} @ {},{}{}",
+                current_color[0], current_color[1], current_color[2], current_color[3], brush_thickness,
+                layers.active_layer().name, viewport.zoom.label(), viewport.offset_x, viewport.offset_y,
+                viewport.cursor_label()
+            );
+            // A message from the last action (such as a rejected colour) leads the line until the next key
+            let info_widget = Paragraph::new(status_line(status_message.as_ref(), info_text)).block(Block::default().borders(Borders::TOP));
+            f.render_widget(info_widget, chunks[1]);
+        })?;
+
+        // A pen left down when a tool was cancelled must not carry into the next one
+        viewport.lift_pen();
+        // The mouse is captured while waiting so palette swatches can be clicked, and
+        // released before a key is handled since many keys open cooked-mode prompts
+        if !mouse_captured {
+            execute!(io::stdout(), EnableMouseCapture)?;
+            mouse_captured = true;
+        }
+        if viewport.poll_event(Duration::from_millis(200))? {
+            let ev = viewport.read_event()?;
+            if matches!(ev, Event::Key(_)) {
+                execute!(io::stdout(), DisableMouseCapture)?;
+                mouse_captured = false;
+                status_message = None;
+            }
+            if viewport.handle_event(&ev, layers.width, layers.height) {
+                continue;
+            }
+            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                continue;
+            }
+            match ev {
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('q'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('Q'),
+                    ..
+                }) => break 'main_loop,
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Esc,
+                    ..
+                }) => {
+                    selection = None;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('c'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                }) => {
+                    if let Some(sel) = &selection {
+                        clipboard = layers.active_layer().canvas.copy_region(sel);
+                    }
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('x'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                }) => {
+                    if let Some(sel) = selection.clone() {
+                        if active_layer_locked(&layers) {
+                            terminal.clear()?;
+                            continue;
+                        }
+                        clipboard = layers.active_layer().canvas.copy_region(&sel);
+                        layers.active_canvas_mut().clear_region(&sel);
+                        history.commit(&layers, "Cut");
+                    }
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('v'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                }) => {
+                    let Some(clip) = clipboard.clone() else {
+                        continue;
+                    };
+                    if active_layer_locked(&layers) {
+                        terminal.clear()?;
+                        continue;
+                    }
+
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    let mut paste_x = viewport.offset_x as i32;
+                    let mut paste_y = viewport.offset_y as i32;
+
+                    viewport.tool_keys = &[KeyCode::Enter];
+                    'paste_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+                        let mut preview = layers.clone();
+                        preview.active_canvas_mut().paste(&clip, paste_x, paste_y);
+                        let outline = Selection::rect(
+                            layers.width, layers.height,
+                            paste_x, paste_y,
+                            paste_x + clip.width as i32 - 1, paste_y + clip.height as i32 - 1,
+                        );
+
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
+                                .split(f.size());
+
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&preview, Some(&outline));
+                            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&preview), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+                            let info = Paragraph::new(format!("[PASTE] Move the mouse to place. Click or Enter to commit, ESC to cancel.{}", viewport.cursor_label()))
+                                .block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::MouseEventKind;
+
+                                    let Some((col, row)) = viewport.mouse_to_canvas(&mouse_event) else {
+                                        continue;
+                                    };
+                                    paste_x = col - clip.width as i32 / 2;
+                                    paste_y = row - clip.height as i32 / 2;
+
+                                    if matches!(mouse_event.kind, MouseEventKind::Down(_)) {
+                                        layers.active_canvas_mut().paste(&clip, paste_x, paste_y);
+                                        selection = Some(Selection::rect(
+                                            layers.width, layers.height,
+                                            paste_x, paste_y,
+                                            paste_x + clip.width as i32 - 1, paste_y + clip.height as i32 - 1,
+                                        ));
+                                        history.commit(&layers, "Paste");
+                                        break 'paste_loop;
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Enter,
+                                    ..
+                                }) => {
+                                    layers = preview;
+                                    selection = Some(outline);
+                                    history.commit(&layers, "Paste");
+                                    break 'paste_loop;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                }) => {
+                                    break 'paste_loop;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    execute!(io::stdout(), DisableMouseCapture)?;
+                    clear_input_buffer();
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('h'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('H'),
+                    ..
+                }) => {
+                    let mut help_scroll: u16 = 0;
+                    'help_loop: loop {
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(1)
+                                .constraints([Constraint::Min(1)])
+                                .split(f.size());
+
+                            let help_text = vec![
+                                Line::from(""),
+                                Line::from("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"),
+                                Line::from("                    HELP MENU"),
+                                Line::from("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"),
+                                Line::from(""),
+                                Line::from("H - Show this help menu"),
+                                Line::from("C - Change brush color (R G B [A], #RRGGBB, hsv(h,s,v), hsl(h,s,l) or a CSS name)"),
+                                Line::from("J - Eyedropper, or Alt+click in paint mode (L in the eyedropper: whole image / active layer)"),
+                                Line::from("W - Colour picker (saturation/value square, hue bar, alpha slider, recent colours)"),
+                                Line::from("S - Draw a shape (E ellipse, R rectangle, O rounded, P polygon, S star)"),
+                                Line::from("    [ ] corner radius or points; F outline/fill/both; 1 or Shift/Ctrl keeps it 1:1"),
+                                Line::from("L - Draw a line (P polyline: double-click or Enter ends, C closes, F fills)"),
+                                Line::from("    B quadratic/cubic Bézier, S spline; drag the handles, Enter draws the curve"),
+                                Line::from("    A toggles anti-aliasing in the line and shape tools (pixel-perfect by default)"),
+                                Line::from("P - Paint mode (draw with mouse drag; A switches to a soft round brush)"),
+                                Line::from("F - Fill tool (T tolerance, 4/8 connectivity, G contiguous/replace everywhere)"),
+                                Line::from("    Tolerance: % of the largest colour distance (R, G, B and alpha) that still matches"),
+                                Line::from("E - Eraser mode (erase to transparency with mouse drag)"),
+                                Line::from("T - Set brush thickness (1-10)"),
+                                Line::from(", / . - Previous / next palette swatch; ; swaps colour and secondary colour"),
+                                Line::from("    Click a swatch: left picks the colour, right picks the secondary colour"),
+                                Line::from("G / B / Delete - Add colour to palette, replace selected swatch, remove it"),
+                                Line::from("} / { - Open / export a palette file (.gpl, .pal, .hex); # - Palette from canvas"),
+                                Line::from("Z - Undo last action"),
+                                Line::from("Y - Redo last action"),
+                                Line::from("U - History browser (jump to any step, switch undo branches)"),
+                                Line::from("A - Select mode (drag a rectangle, drag inside it to move)"),
+                                Line::from("    R/W/L - Rectangle, magic wand, lasso; T tolerance, G contiguous/global"),
+                                Line::from("    N/U/S/I or Shift/Ctrl/Alt+click - Replace, add, subtract, intersect"),
+                                Line::from("Ctrl+C / Ctrl+X / Ctrl+V - Copy, cut, paste the selection (Esc deselects)"),
+                                Line::from("N - New layer above the active one"),
+                                Line::from("X - Delete active layer"),
+                                Line::from("D - Duplicate active layer"),
+                                Line::from("M - Merge active layer down"),
+                                Line::from("> / < - Move active layer up / down"),
+                                Line::from("Tab / Shift+Tab - Select layer above / below"),
+                                Line::from("V - Toggle layer visibility"),
+                                Line::from("O - Set layer opacity (0-100)"),
+                                Line::from("K - Lock or unlock active layer"),
+                                Line::from("R - Rename active layer"),
+                                Line::from("[ - Export image as .rai or .png file (supports paths and ~)"),
+                                Line::from("] - Open and load a .rai or .png file (supports paths and ~)"),
+                                Line::from("* - Save to the current file (asks for a path if there is none yet)"),
+                                Line::from("Arrows / mouse wheel / middle-drag - Pan the view (Shift+wheel pans sideways)"),
+                                Line::from("+ / - - Zoom in / out (4 cells, 2 cells, 1 cell, half-block; the mouse can't draw at half-block)"),
+                                Line::from("I - Toggle keyboard cursor: arrows move (Shift+arrow by 8), Space clicks, Enter toggles"),
+                                Line::from("    pen down (drag); hjkl move too, and Enter is the pen, in tools that don't use them"),
+                                Line::from("Q - Quit the application"),
+                                Line::from(""),
+                                Line::from("Up/Down scrolls, any other key exits the help menu..."),
+                                Line::from(""),
+                            ];
+
+                            // Keep the last line at the bottom edge when scrolled all the way down
+                            let visible = chunks[0].height.saturating_sub(2);
+                            help_scroll = help_scroll.min((help_text.len() as u16).saturating_sub(visible));
+                            let help_widget = Paragraph::new(help_text)
+                                .scroll((help_scroll, 0))
+                                .block(Block::default().borders(Borders::ALL).title(" Help "));
+                            f.render_widget(help_widget, chunks[0]);
+                        })?;
+
+                        if event::poll(Duration::from_millis(50))? {
+                            match event::read()? {
+                                Event::Key(KeyEvent { code: KeyCode::Up, .. }) => {
+                                    help_scroll = help_scroll.saturating_sub(1);
+                                }
+                                Event::Key(KeyEvent { code: KeyCode::Down, .. }) => {
+                                    help_scroll += 1;
+                                }
+                                Event::Key(_) => {
+                                    break 'help_loop;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('z'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('Z'),
+                    ..
+                }) => {
+                    if let Some(restored) = history.undo() {
+                        layers = restored.clone();
+                    }
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('y'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('Y'),
+                    ..
+                }) => {
+                    if let Some(restored) = history.redo() {
+                        layers = restored.clone();
+                    }
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('t'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('T'),
+                    ..
+                }) => {
+                    let input = prompt("Brush thickness (1-10): ");
+                    if let Ok(t) = input.parse::<usize>() {
+                        brush_thickness = clamp(t, 1, 10);
+                    }
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('c'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('C'),
+                    ..
+                }) => {
+                    let input = prompt("Colour (R G B [A], #RRGGBB, hsv(h,s,v), hsl(h,s,l) or a name like tomato): ");
+                    if !input.is_empty() {
+                        match parse_color(&input) {
+                            Ok(color) => current_color = color,
+                            Err(e) => status_message = Some(format!("Colour not changed: {}", e)),
+                        }
+                    }
+                    terminal.clear()?;
+                }
 
-        if event::poll(Duration::from_millis(200))? {
-            match event::read()? {
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('q'),
+                    code: KeyCode::Char('w'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('Q'),
+                    code: KeyCode::Char('W'),
                     ..
-                }) => break 'main_loop,
+                }) => {
+                    let mut picker = ColorPicker::new(current_color);
+                    execute!(io::stdout(), EnableMouseCapture)?;
+
+                    'picker_loop: loop {
+                        terminal.draw(|f| {
+                            picker.layout(f.size());
+                            for (widget, area) in picker.widgets(f.size(), &recent_colors) {
+                                f.render_widget(widget, area);
+                            }
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(200))? {
+                            let ev = viewport.read_event()?;
+                            match ev {
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Enter,
+                                    ..
+                                }) => {
+                                    current_color = picker.color();
+                                    break 'picker_loop;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                }) => break 'picker_loop,
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('c'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('C'),
+                                    ..
+                                }) => {
+                                    execute!(io::stdout(), DisableMouseCapture)?;
+                                    let input = prompt("Colour (R G B [A], #RRGGBB, hsv(h,s,v), hsl(h,s,l) or a name): ");
+                                    execute!(io::stdout(), EnableMouseCapture)?;
+                                    picker.message = None;
+                                    if !input.is_empty() {
+                                        match parse_color(&input) {
+                                            Ok(color) => picker.set_color(color),
+                                            Err(e) => picker.message = Some(format!("Colour not changed: {}", e)),
+                                        }
+                                    }
+                                    terminal.clear()?;
+                                }
+                                Event::Key(key) => {
+                                    picker.message = None;
+                                    picker.handle_key(&key, &recent_colors);
+                                }
+                                Event::Mouse(mouse_event) => picker.handle_mouse(&mouse_event, &recent_colors),
+                                _ => {}
+                            }
+                        }
+                    }
+                    execute!(io::stdout(), DisableMouseCapture)?;
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('s'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('S'),
+                    ..
+                }) => {
+                    if active_layer_locked(&layers) {
+                        terminal.clear()?;
+                        continue;
+                    }
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    let mut start_pos: Option<(i32, i32)> = None;
+                    let mut end_pos: Option<(i32, i32)> = None;
+                    let mut modifier_held = false;
+                    let mut canvas_height = 0;
+
+                    viewport.tool_keys = &[];
+                    'shape_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+                        let mut preview = layers.clone();
+                        let constrain = shape_options.constrain || modifier_held;
+
+                        if let (Some(start), Some(end)) = (start_pos, end_pos) {
+                            let end = if constrain { constrain_square(start, end) } else { end };
+                            draw_shape(preview.active_canvas_mut(), start, end, shape_options, brush_thickness, current_color, secondary_color);
+                            preview.active_canvas_mut().clip_to_selection(&layers.active_layer().canvas, selection.as_ref());
+                        }
+
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(3)])
+                                .split(f.size());
+
+                            canvas_height = chunks[0].height as usize;
+
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&preview, selection.as_ref());
+                            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&preview), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+                            let hint = if start_pos.is_some() && end_pos.is_none() {
+                                "Move to resize. Click to finalize."
+                            } else {
+                                "Click and drag from corner to corner."
+                            };
+                            let info = Paragraph::new(vec![
+                                Line::from(format!("[SHAPE] {} Press ESC to cancel.{}", hint, viewport.cursor_label())),
+                                Line::from(shape_options.label()),
+                            ])
+                            .block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::MouseEventKind;
+
+                                    modifier_held = mouse_event.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::CONTROL);
+                                    let pos = viewport.mouse_to_canvas(&mouse_event);
+                                    match mouse_event.kind {
+                                        MouseEventKind::Down(_) => {
+                                            if start_pos.is_none() {
+                                                start_pos = pos;
+                                            } else {
+                                                end_pos = pos.or(end_pos);
+                                            }
+                                        }
+                                        MouseEventKind::Drag(_) if start_pos.is_some() && end_pos.is_none() => {
+                                            end_pos = pos.or(end_pos);
+                                        }
+                                        MouseEventKind::Moved if start_pos.is_some() && end_pos.is_some() => {
+                                            end_pos = pos.or(end_pos);
+                                        }
+                                        MouseEventKind::Up(_) => {
+                                            if let (Some(start), Some(end)) = (start_pos, end_pos) {
+                                                let constrain = shape_options.constrain || modifier_held;
+                                                let end = if constrain { constrain_square(start, end) } else { end };
+                                                let before = layers.active_layer().canvas.clone();
+                                                draw_shape(layers.active_canvas_mut(), start, end, shape_options, brush_thickness, current_color, secondary_color);
+                                                layers.active_canvas_mut().clip_to_selection(&before, selection.as_ref());
+                                                history.commit(&layers, shape_options.name());
+                                                break 'shape_loop;
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('e'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('E'),
+                                    ..
+                                }) => {
+                                    shape_options.kind = ShapeKind::Ellipse;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('r'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('R'),
+                                    ..
+                                }) => {
+                                    shape_options.kind = ShapeKind::Rectangle;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('o'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('O'),
+                                    ..
+                                }) => {
+                                    shape_options.kind = ShapeKind::RoundedRectangle;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('p'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('P'),
+                                    ..
+                                }) => {
+                                    shape_options.kind = ShapeKind::Polygon;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('s'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('S'),
+                                    ..
+                                }) => {
+                                    shape_options.kind = ShapeKind::Star;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('f'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('F'),
+                                    ..
+                                }) => {
+                                    shape_options.style = match shape_options.style {
+                                        ShapeStyle::Outline => ShapeStyle::Fill,
+                                        ShapeStyle::Fill => ShapeStyle::OutlineAndFill,
+                                        ShapeStyle::OutlineAndFill => ShapeStyle::Outline,
+                                    };
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('['),
+                                    ..
+                                }) => {
+                                    shape_options.adjust(-1);
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char(']'),
+                                    ..
+                                }) => {
+                                    shape_options.adjust(1);
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('1'),
+                                    ..
+                                }) => {
+                                    shape_options.constrain = !shape_options.constrain;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('a'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('A'),
+                                    ..
+                                }) => {
+                                    shape_options.antialias = !shape_options.antialias;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                }) => {
+                                    break 'shape_loop;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    execute!(io::stdout(), DisableMouseCapture)?;
+                    clear_input_buffer();
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('l'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('L'),
+                    ..
+                }) => {
+                    if active_layer_locked(&layers) {
+                        terminal.clear()?;
+                        continue;
+                    }
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    let mut points: Vec<(i32, i32)> = Vec::new();
+                    let mut hover: Option<(i32, i32)> = None;
+                    let mut last_click: Option<(std::time::Instant, (i32, i32))> = None;
+                    // Index of the curve handle being dragged
+                    let mut dragging: Option<usize> = None;
+
+                    viewport.tool_keys = &[KeyCode::Enter];
+                    'line_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+                        let mut preview = layers.clone();
+                        if !points.is_empty() {
+                            let mut path = points.clone();
+                            let placing = line_options.mode.max_points().is_none_or(|max| points.len() < max);
+                            if dragging.is_none() && placing {
+                                path.extend(hover);
+                            }
+                            line_options.draw(preview.active_canvas_mut(), &path, brush_thickness, current_color, secondary_color);
+                            preview.active_canvas_mut().clip_to_selection(&layers.active_layer().canvas, selection.as_ref());
+                        }
+                        viewport.markers = if line_options.mode.is_curve() {
+                            points.iter().map(|&(x, y)| (x as usize, y as usize)).collect()
+                        } else {
+                            HashSet::new()
+                        };
+
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(3)])
+                                .split(f.size());
+
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&preview, selection.as_ref());
+                            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&preview), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+                            let hint = match (line_options.mode, points.len()) {
+                                (_, 0) => "Click startpoint.",
+                                (LineMode::Single, _) | (LineMode::QuadraticBezier | LineMode::CubicBezier, 1) => "Click endpoint.",
+                                (LineMode::Polyline, _) => "Click to add points, double-click or Enter to finish, Backspace to undo a point.",
+                                (LineMode::Spline, _) => "Click points to pass through, drag them to adjust, double-click or Enter to finish.",
+                                (mode, n) if mode.max_points().is_some_and(|max| n < max) => "Click a control point, or Enter to draw.",
+                                _ => "Drag the handles to adjust, Enter to draw, Backspace to undo a point.",
+                            };
+                            let info = Paragraph::new(vec![
+                                Line::from(format!("[LINE] {} Press ESC to cancel.{}", hint, viewport.cursor_label())),
+                                Line::from(line_options.label()),
+                            ])
+                            .block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            let mut finish = false;
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::MouseEventKind;
+
+                                    let pos = viewport.mouse_to_canvas(&mouse_event);
+                                    match mouse_event.kind {
+                                        MouseEventKind::Moved | MouseEventKind::Drag(_) => {
+                                            hover = pos.or(hover);
+                                            if let (Some(i), Some(pos)) = (dragging, pos) {
+                                                points[i] = pos;
+                                            }
+                                        }
+                                        MouseEventKind::Down(_) => {
+                                            let Some(pos) = pos else {
+                                                continue;
+                                            };
+                                            let double_click = last_click.is_some_and(|(at, last)| last == pos && at.elapsed() < DOUBLE_CLICK_TIME);
+                                            last_click = Some((std::time::Instant::now(), pos));
+                                            // Curve handles can be grabbed from a pixel away
+                                            let handle = points
+                                                .iter()
+                                                .enumerate()
+                                                .filter(|(_, p)| (p.0 - pos.0).abs() <= 1 && (p.1 - pos.1).abs() <= 1)
+                                                .min_by_key(|(_, p)| (p.0 - pos.0).abs() + (p.1 - pos.1).abs())
+                                                .map(|(i, _)| i);
+                                            let mode = line_options.mode;
+                                            if double_click && matches!(mode, LineMode::Polyline | LineMode::Spline) {
+                                                finish = true;
+                                            } else if mode.is_curve() && handle.is_some() {
+                                                dragging = handle;
+                                            } else if points.last() != Some(&pos) && mode.max_points().is_none_or(|max| points.len() < max) {
+                                                points.push(pos);
+                                                finish = mode == LineMode::Single && points.len() == 2;
+                                            }
+                                            hover = Some(pos);
+                                        }
+                                        MouseEventKind::Up(_) => {
+                                            dragging = None;
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Enter,
+                                    ..
+                                }) => {
+                                    finish = true;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Backspace,
+                                    ..
+                                }) => {
+                                    points.pop();
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('p'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('P'),
+                                    ..
+                                }) => {
+                                    line_options.mode = if line_options.mode == LineMode::Polyline { LineMode::Single } else { LineMode::Polyline };
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('b'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('B'),
+                                    ..
+                                }) => {
+                                    line_options.mode = match line_options.mode {
+                                        LineMode::QuadraticBezier => LineMode::CubicBezier,
+                                        LineMode::CubicBezier => LineMode::Single,
+                                        _ => LineMode::QuadraticBezier,
+                                    };
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('s'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('S'),
+                                    ..
+                                }) => {
+                                    line_options.mode = if line_options.mode == LineMode::Spline { LineMode::Single } else { LineMode::Spline };
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('c'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('C'),
+                                    ..
+                                }) => {
+                                    line_options.closed = !line_options.closed;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('a'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('A'),
+                                    ..
+                                }) => {
+                                    line_options.antialias = !line_options.antialias;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('f'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('F'),
+                                    ..
+                                }) => {
+                                    line_options.style = match line_options.style {
+                                        ShapeStyle::Outline => ShapeStyle::Fill,
+                                        ShapeStyle::Fill => ShapeStyle::OutlineAndFill,
+                                        ShapeStyle::OutlineAndFill => ShapeStyle::Outline,
+                                    };
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                }) => {
+                                    break 'line_loop;
+                                }
+                                _ => {}
+                            }
+                            if let Some(max) = line_options.mode.max_points() {
+                                points.truncate(max);
+                            }
+                            dragging = dragging.filter(|&i| i < points.len());
+                            if finish && points.len() >= 2 {
+                                let before = layers.active_layer().canvas.clone();
+                                line_options.draw(layers.active_canvas_mut(), &points, brush_thickness, current_color, secondary_color);
+                                layers.active_canvas_mut().clip_to_selection(&before, selection.as_ref());
+                                history.commit(&layers, line_options.history_name());
+                                break 'line_loop;
+                            }
+                        }
+                    }
+                    viewport.markers.clear();
+                    execute!(io::stdout(), DisableMouseCapture)?;
+                    clear_input_buffer();
+                    terminal.clear()?;
+                }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('h'),
+                    code: KeyCode::Char('f'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('H'),
+                    code: KeyCode::Char('F'),
                     ..
                 }) => {
-                    'help_loop: loop {
+                    if active_layer_locked(&layers) {
+                        terminal.clear()?;
+                        continue;
+                    }
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    viewport.tool_keys = &[];
+                    'fill_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
                         terminal.draw(|f| {
                             let chunks = Layout::default()
                                 .direction(Direction::Vertical)
-                                .margin(1)
-                                .constraints([Constraint::Min(1)])
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
                                 .split(f.size());
 
-                            let help_text = vec![
-                                Line::from(""),
-                                Line::from("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"),
-                                Line::from("                    HELP MENU"),
-                                Line::from("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"),
-                                Line::from(""),
-                                Line::from("H - Show this help menu"),
-                                Line::from("C - Change brush color (RGB values)"),
-                                Line::from("S - Draw a shape (circle or square)"),
-                                Line::from("L - Draw a line"),
-                                Line::from("P - Paint mode (draw with mouse drag)"),
-                                Line::from("F - Fill tool"),
-                                Line::from("E - Eraser mode (erase with mouse drag)"),
-                                Line::from("T - Set brush thickness (1-10)"),
-                                Line::from("Z - Undo last action"),
-                                Line::from("Y - Redo last action"),
-                                Line::from("[ - Export image as .rai file (supports paths and ~)"),
-                                Line::from("] - Open and load a .rai file (supports paths and ~)"),
-                                Line::from("* - Save to existing .rai file (supports paths and ~)"),
-                                Line::from("Q - Quit the application"),
-                                Line::from(""),
-                                Line::from("Press any key to exit help menu..."),
-                                Line::from(""),
-                            ];
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&layers, selection.as_ref());
+                            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&layers), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
 
-                            let help_widget = Paragraph::new(help_text)
-                                .block(Block::default().borders(Borders::ALL).title(" Help "));
-                            f.render_widget(help_widget, chunks[0]);
+                            let info_text = format!("[FILL] Click to fill area. {} | ESC to cancel.{}", fill_options.label(), viewport.cursor_label());
+                            let info = Paragraph::new(status_line(status_message.as_ref(), info_text)).block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
                         })?;
 
-                        if event::poll(Duration::from_millis(50))? {
-                            match event::read()? {
-                                Event::Key(_) => {
-                                    break 'help_loop;
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if matches!(ev, Event::Key(_)) {
+                                status_message = None;
+                            }
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::MouseEventKind;
+
+                                    if matches!(mouse_event.kind, MouseEventKind::Down(_)) {
+                                        let Some((col, row)) = viewport.mouse_to_canvas(&mouse_event) else {
+                                            continue;
+                                        };
+                                        flood_fill(layers.active_canvas_mut(), col, row, current_color, selection.as_ref(), fill_options);
+                                        history.commit(&layers, "Fill");
+                                        break 'fill_loop;
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('t'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('T'),
+                                    ..
+                                }) => {
+                                    execute!(io::stdout(), DisableMouseCapture)?;
+                                    if let Some(t) = prompt_tolerance("Fill", &mut status_message) {
+                                        fill_options.tolerance = t;
+                                    }
+                                    execute!(io::stdout(), EnableMouseCapture)?;
+                                    terminal.clear()?;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('4'),
+                                    ..
+                                }) => {
+                                    fill_options.diagonal = false;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('8'),
+                                    ..
+                                }) => {
+                                    fill_options.diagonal = true;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('g'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('G'),
+                                    ..
+                                }) => {
+                                    fill_options.global = !fill_options.global;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                }) => {
+                                    break 'fill_loop;
                                 }
                                 _ => {}
                             }
                         }
                     }
+                    execute!(io::stdout(), DisableMouseCapture)?;
+                    clear_input_buffer();
                     terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('z'),
+                    code: KeyCode::Char('j'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('Z'),
+                    code: KeyCode::Char('J'),
                     ..
                 }) => {
-                    if history_index > 0 {
-                        history_index -= 1;
-                        canvas = canvas_history[history_index].clone_for_preview();
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    viewport.tool_keys = &[KeyCode::Char('l')];
+                    'eyedropper_loop: loop {
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
+                                .split(f.size());
+
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&layers, selection.as_ref());
+                            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&layers), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+                            let source = if sample_composite { "the whole image" } else { "the active layer" };
+                            let info = Paragraph::new(format!(
+                                "[EYEDROPPER] Click to take a colour from {} (right click: secondary). L switches source. ESC to cancel.{}",
+                                source,
+                                viewport.cursor_label()
+                            ))
+                            .block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::{MouseButton, MouseEventKind};
+
+                                    if let MouseEventKind::Down(button) = mouse_event.kind {
+                                        // Clicks beside a small canvas would otherwise pick up transparency
+                                        let Some((col, row)) = viewport.mouse_to_canvas(&mouse_event).filter(|&(x, y)| layers.contains(x, y)) else {
+                                            continue;
+                                        };
+                                        let sampled = layers.sample(col as usize, row as usize, sample_composite);
+                                        if button == MouseButton::Right {
+                                            secondary_color = sampled;
+                                        } else {
+                                            current_color = sampled;
+                                        }
+                                        break 'eyedropper_loop;
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('l'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('L'),
+                                    ..
+                                }) => {
+                                    sample_composite = !sample_composite;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                }) => {
+                                    break 'eyedropper_loop;
+                                }
+                                _ => {}
+                            }
+                        }
                     }
+                    execute!(io::stdout(), DisableMouseCapture)?;
+                    clear_input_buffer();
+                    terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('y'),
+                    code: KeyCode::Char('p'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('Y'),
+                    code: KeyCode::Char('P'),
                     ..
                 }) => {
-                    if history_index < canvas_history.len() - 1 {
-                        history_index += 1;
-                        canvas = canvas_history[history_index].clone_for_preview();
+                    if active_layer_locked(&layers) {
+                        terminal.clear()?;
+                        continue;
                     }
-                }
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    let mut last_pos: Option<(i32, i32)> = None;
+                    let stroke_base = layers.active_layer().canvas.clone();
+                    let mut soft_stroke: Option<(Canvas, Coverage)> = None;
+                    viewport.tool_keys = &[];
+                    'paint_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
+                                .split(f.size());
 
-                Event::Key(KeyEvent {
-                    code: KeyCode::Char('t'),
-                    ..
-                })
-                | Event::Key(KeyEvent {
-                    code: KeyCode::Char('T'),
-                    ..
-                }) => {
-                    let input = prompt("Brush thickness (1-10): ");
-                    if let Ok(t) = input.parse::<usize>() {
-                        brush_thickness = clamp(t, 1, 10);
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&layers, selection.as_ref());
+                            let canvas_widget =
+                                Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&layers), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+                            let brush = if soft_brush { "Soft round brush" } else { "Square brush" };
+                            let info = Paragraph::new(format!("[PAINT MODE] Click/drag to draw, Alt+click to take a colour. {} (A). Press ESC or P to exit.{}", brush, viewport.cursor_label()))
+                                .block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::{MouseButton, MouseEventKind};
+                                    
+                                    let sampling = mouse_event.modifiers.contains(KeyModifiers::ALT);
+                                    match mouse_event.kind {
+                                        // Alt+click is a quick eyedropper; its drag does not paint
+                                        MouseEventKind::Down(button) if sampling => {
+                                            // Clicks beside a small canvas would otherwise pick up transparency
+                                            let Some((col, row)) = viewport.mouse_to_canvas(&mouse_event).filter(|&(x, y)| layers.contains(x, y)) else {
+                                                continue;
+                                            };
+                                            let sampled = layers.sample(col as usize, row as usize, sample_composite);
+                                            if button == MouseButton::Right {
+                                                secondary_color = sampled;
+                                            } else {
+                                                current_color = sampled;
+                                            }
+                                        }
+                                        MouseEventKind::Drag(_) if sampling => {}
+                                        MouseEventKind::Down(_) | MouseEventKind::Drag(_) => {
+                                            let Some((col, row)) = viewport.mouse_to_canvas(&mouse_event) else {
+                                                continue;
+                                            };
+
+                                            if soft_brush {
+                                                // Coverage builds up over the whole stroke, then is blended over
+                                                // the canvas as it was when the stroke started
+                                                let canvas = layers.active_canvas_mut();
+                                                let (base, coverage) = soft_stroke.get_or_insert_with(|| (canvas.clone(), Coverage::new(canvas.width, canvas.height)));
+                                                let (from_x, from_y) = last_pos.unwrap_or((col, row));
+                                                coverage.capsule((from_x as f64, from_y as f64), (col as f64, row as f64), brush_thickness as f64 / 2.0);
+                                                coverage.apply(canvas, Some(base), current_color);
+                                            } else if let Some((last_x, last_y)) = last_pos {
+                                                draw_line_with_brush(layers.active_canvas_mut(), last_x, last_y, col, row, brush_thickness, current_color);
+                                            } else {
+                                                draw_brush_stroke(layers.active_canvas_mut(), col as usize, row as usize, brush_thickness, current_color);
+                                            }
+                                            layers.active_canvas_mut().clip_to_selection(&stroke_base, selection.as_ref());
+                                            last_pos = Some((col, row));
+                                        }
+                                        MouseEventKind::Up(_) => {
+                                            last_pos = None;
+                                            soft_stroke = None;
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('a'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('A'),
+                                    ..
+                                }) => {
+                                    soft_brush = !soft_brush;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('p'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('P'),
+                                    ..
+                                }) => {
+                                    execute!(io::stdout(), DisableMouseCapture)?;
+                                    clear_input_buffer();
+                                    history.commit(&layers, "Paint stroke");
+                                    terminal.clear()?;
+                                    break 'paint_loop;
+                                }
+                                _ => {}
+                            }
+                        }
                     }
-                    terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('c'),
+                    code: KeyCode::Char('e'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('C'),
+                    code: KeyCode::Char('E'),
                     ..
                 }) => {
-                    let input = prompt("RGB values (R G B): ");
-                    let parts: Vec<&str> = input.split_whitespace().collect();
-
-                    if parts.len() >= 3 {
-                        if let (Ok(r), Ok(g), Ok(b)) = (
-                            parts[0].parse::<u8>(),
-                            parts[1].parse::<u8>(),
-                            parts[2].parse::<u8>(),
-                        ) {
-                            current_color = [r, g, b];
+                    if active_layer_locked(&layers) {
+                        terminal.clear()?;
+                        continue;
+                    }
+                    execute!(io::stdout(), EnableMouseCapture)?;
+                    let mut last_pos: Option<(i32, i32)> = None;
+                    let stroke_base = layers.active_layer().canvas.clone();
+                    viewport.tool_keys = &[];
+                    'erase_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+                        terminal.draw(|f| {
+                            let chunks = Layout::default()
+                                .direction(Direction::Vertical)
+                                .margin(0)
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
+                                .split(f.size());
+
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&layers, selection.as_ref());
+                            let canvas_widget =
+                                Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&layers), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
+
+                            let info = Paragraph::new(format!("[ERASER MODE] Click/drag to erase. Press ESC or E to exit.{}", viewport.cursor_label()))
+                                .block(Block::default().borders(Borders::TOP));
+                            f.render_widget(info, chunks[1]);
+                        })?;
+
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
+                                Event::Mouse(mouse_event) => {
+                                    use crossterm::event::MouseEventKind;
+                                    
+                                    match mouse_event.kind {
+                                        MouseEventKind::Down(_) | MouseEventKind::Drag(_) => {
+                                            let Some((col, row)) = viewport.mouse_to_canvas(&mouse_event) else {
+                                                continue;
+                                            };
+
+                                            if let Some((last_x, last_y)) = last_pos {
+                                                draw_line_with_brush(layers.active_canvas_mut(), last_x, last_y, col, row, brush_thickness, TRANSPARENT);
+                                            } else {
+                                                draw_brush_stroke(layers.active_canvas_mut(), col as usize, row as usize, brush_thickness, TRANSPARENT);
+                                            }
+                                            layers.active_canvas_mut().clip_to_selection(&stroke_base, selection.as_ref());
+                                            last_pos = Some((col, row));
+                                        }
+                                        MouseEventKind::Up(_) => {
+                                            last_pos = None;
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Esc,
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('e'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('E'),
+                                    ..
+                                }) => {
+                                    execute!(io::stdout(), DisableMouseCapture)?;
+                                    clear_input_buffer();
+                                    history.commit(&layers, "Erase");
+                                    terminal.clear()?;
+                                    break 'erase_loop;
+                                }
+                                _ => {}
+                            }
                         }
                     }
-                    terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('s'),
+                    code: KeyCode::Char('a'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('S'),
+                    code: KeyCode::Char('A'),
                     ..
                 }) => {
-                    let shape_type = prompt("Shape (c=circle/s=square): ").to_lowercase();
-                    let is_circle = shape_type.starts_with('c');
-
                     execute!(io::stdout(), EnableMouseCapture)?;
-                    let mut start_pos: Option<(usize, usize)> = None;
-                    let mut end_pos: Option<(usize, usize)> = None;
-                    let mut canvas_height = 0;
+                    let mut drag_start: Option<(i32, i32)> = None;
+                    let mut floating: Option<FloatingPixels> = None;
+                    let mut tool = SelectTool::Rect;
+                    let mut mode_op = SelectionOp::Replace;
+                    let mut tolerance: u8 = 0;
+                    let mut contiguous = true;
+                    // Selection as it was when the current drag started, so the
+                    // rect/lasso preview can be recombined on every mouse move.
+                    let mut base_selection: Option<Selection> = None;
+                    let mut drag_op = SelectionOp::Replace;
+                    let mut lasso_points: Vec<(i32, i32)> = Vec::new();
 
-                    'shape_loop: loop {
-                        let mut preview_canvas = canvas.clone_for_preview();
-
-                        if let (Some((sx, sy)), Some((ex, ey))) = (start_pos, end_pos) {
-                            let sx_px = (sx / 2) as i32;
-                            let sy_px = sy as i32;
-                            let ex_px = (ex / 2) as i32;
-                            let ey_px = ey as i32;
-                            
-                            let dx = (ex_px - sx_px).abs();
-                            let dy = (ey_px - sy_px).abs();
-                            let cx = ((sx_px + ex_px) / 2) as i32;
-                            let cy = ((sy_px + ey_px) / 2) as i32;
-
-                            if is_circle {
-                                let r = (dx.max(dy) / 2).max(1);
-                                draw_circle(&mut preview_canvas, cx, cy, r, current_color);
-                            } else {
-                                let hx = (dx / 2) as i32;
-                                let hy = (dy / 2) as i32;
-                                draw_rect_preview(&mut preview_canvas, cx, cy, hx.max(1), hy.max(1), current_color);
-                            }
+                    viewport.tool_keys = &[KeyCode::Char('l')];
+                    'select_loop: loop {
+                        autosave.tick(&layers, &history, &palette.colors, save_target.as_ref());
+                        let mut preview = layers.clone();
+                        let mut outline = selection.clone();
+                        if let Some(float) = &floating {
+                            preview = float.base.clone();
+                            preview.active_canvas_mut().paste(&float.pixels, float.x, float.y);
+                            outline = selection.as_ref().map(|s| s.translated(float.x - float.origin.0, float.y - float.origin.1));
+                        }
+                        // A lasso drag shows just its path; the mask is built when the button is released
+                        if !lasso_points.is_empty() && drag_op == SelectionOp::Replace {
+                            outline = None;
                         }
+                        viewport.markers = lasso_trail(&lasso_points);
 
                         terminal.draw(|f| {
                             let chunks = Layout::default()
                                 .direction(Direction::Vertical)
                                 .margin(0)
-                                .constraints([Constraint::Min(1), Constraint::Length(3)])
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
                                 .split(f.size());
 
-                            canvas_height = chunks[0].height as usize;
-
-                            let canvas_spans = preview_canvas.render_to_spans();
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&preview, outline.as_ref());
                             let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
-                            f.render_widget(canvas_widget, chunks[0]);
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(layer_panel(&preview), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
 
-                            let shape_name = if is_circle { "CIRCLE" } else { "SQUARE" };
-                            let info = if start_pos.is_some() && end_pos.is_none() {
-                                Paragraph::new(format!("[{}] Move to resize. Click to finalize. Press ESC to cancel.", shape_name)).block(Block::default().borders(Borders::TOP))
-                            } else {
-                                Paragraph::new(format!("[{}] Click and drag. Press ESC to cancel.", shape_name)).block(Block::default().borders(Borders::TOP))
-                            };
+                            let info_text = format!(
+                                "[SELECT {}] Op: {} | Tolerance: {}% of RGBA distance | {} | R/W/L tool, N/U/S/I op, T tolerance, G toggle, ESC or A to exit{}",
+                                tool.label(),
+                                mode_op.label(),
+                                tolerance,
+                                if contiguous { "contiguous" } else { "global" },
+                                viewport.cursor_label(),
+                            );
+                            let info = Paragraph::new(status_line(status_message.as_ref(), info_text)).block(Block::default().borders(Borders::TOP));
                             f.render_widget(info, chunks[1]);
                         })?;
 
-                        if event::poll(Duration::from_millis(50))? {
-                            match event::read()? {
+                        if viewport.poll_event(Duration::from_millis(50))? {
+                            let ev = viewport.read_event()?;
+                            if matches!(ev, Event::Key(_)) {
+                                status_message = None;
+                            }
+                            if viewport.handle_event(&ev, layers.width, layers.height) {
+                                continue;
+                            }
+                            if palette.handle_event(&ev, &mut current_color, &mut secondary_color) {
+                                continue;
+                            }
+                            match ev {
                                 Event::Mouse(mouse_event) => {
                                     use crossterm::event::MouseEventKind;
 
+                                    let pos = viewport.mouse_to_canvas(&mouse_event);
                                     match mouse_event.kind {
                                         MouseEventKind::Down(_) => {
-                                            if start_pos.is_none() {
-                                                start_pos = Some((mouse_event.column as usize, mouse_event.row as usize));
+                                            let Some((col, row)) = pos else {
+                                                continue;
+                                            };
+                                            let op = if mouse_event.modifiers.contains(KeyModifiers::SHIFT) {
+                                                SelectionOp::Add
+                                            } else if mouse_event.modifiers.contains(KeyModifiers::CONTROL) {
+                                                SelectionOp::Subtract
+                                            } else if mouse_event.modifiers.contains(KeyModifiers::ALT) {
+                                                SelectionOp::Intersect
                                             } else {
-                                                end_pos = Some((mouse_event.column as usize, mouse_event.row as usize));
+                                                mode_op
+                                            };
+                                            let lift = selection
+                                                .clone()
+                                                .filter(|s| tool == SelectTool::Rect && op == SelectionOp::Replace && s.contains(col, row) && !layers.active_layer().locked);
+                                            if let Some(sel) = lift {
+                                                if let (Some(pixels), Some((bx, by, _, _))) = (layers.active_layer().canvas.copy_region(&sel), sel.bounds()) {
+                                                    let mut base = layers.clone();
+                                                    base.active_canvas_mut().clear_region(&sel);
+                                                    let (bx, by) = (bx as i32, by as i32);
+                                                    floating = Some(FloatingPixels { pixels, base, origin: (bx, by), x: bx, y: by, grab: (col - bx, row - by) });
+                                                }
+                                                continue;
                                             }
-                                        }
-                                        MouseEventKind::Drag(_) => {
-                                            if start_pos.is_some() && end_pos.is_none() {
-                                                end_pos = Some((mouse_event.column as usize, mouse_event.row as usize));
+
+                                            base_selection = selection.clone();
+                                            drag_op = op;
+                                            match tool {
+                                                SelectTool::Rect => {
+                                                    drag_start = Some((col, row));
+                                                    let rect = Selection::rect(layers.width, layers.height, col, row, col, row);
+                                                    selection = Selection::combine(base_selection.as_ref(), rect, op);
+                                                }
+                                                SelectTool::Wand => {
+                                                    let canvas = &layers.active_layer().canvas;
+                                                    let region = if contiguous {
+                                                        connected_region(canvas, col, row, tolerance, false, None)
+                                                    } else {
+                                                        matching_pixels(canvas, col, row, tolerance)
+                                                    };
+                                                    selection = Selection::combine(base_selection.as_ref(), region, op);
+                                                }
+                                                SelectTool::Lasso => {
+                                                    lasso_points = vec![(col, row)];
+                                                }
                                             }
                                         }
-                                        MouseEventKind::Moved => {
-                                            if start_pos.is_some() && end_pos.is_some() {
-                                                end_pos = Some((mouse_event.column as usize, mouse_event.row as usize));
+                                        MouseEventKind::Drag(_) => {
+                                            let Some((col, row)) = pos else {
+                                                continue;
+                                            };
+                                            if let Some(float) = &mut floating {
+                                                float.x = col - float.grab.0;
+                                                float.y = row - float.grab.1;
+                                            } else if let Some((sx, sy)) = drag_start {
+                                                let rect = Selection::rect(layers.width, layers.height, sx, sy, col, row);
+                                                selection = Selection::combine(base_selection.as_ref(), rect, drag_op);
+                                            } else if !lasso_points.is_empty() && lasso_points.last() != Some(&(col, row)) {
+                                                lasso_points.push((col, row));
                                             }
                                         }
                                         MouseEventKind::Up(_) => {
-                                            if let (Some((sx, sy)), Some((ex, ey))) = (start_pos, end_pos) {
-                                                let sx_px = (sx / 2) as i32;
-                                                let sy_px = sy as i32;
-                                                let ex_px = (ex / 2) as i32;
-                                                let ey_px = ey as i32;
-                                                
-                                                let dx = (ex_px - sx_px).abs();
-                                                let dy = (ey_px - sy_px).abs();
-                                                let cx = ((sx_px + ex_px) / 2) as i32;
-                                                let cy = ((sy_px + ey_px) / 2) as i32;
-
-                                                if is_circle {
-                                                    let r = (dx.max(dy) / 2).max(1);
-                                                    draw_circle(&mut canvas, cx, cy, r, current_color);
-                                                } else {
-                                                    let hx = (dx / 2) as i32;
-                                                    let hy = (dy / 2) as i32;
-                                                    draw_rect_preview(&mut canvas, cx, cy, hx.max(1), hy.max(1), current_color);
-                                                }
-                                                canvas_history.truncate(history_index + 1);
-                                                canvas_history.push(canvas.clone_for_preview());
-                                                history_index = canvas_history.len() - 1;
-                                                break 'shape_loop;
+                                            // A click that never moved the float just puts it back, so leave
+                                            // layers untouched and keep the history free of empty moves
+                                            if let Some(mut float) = floating.take() && (float.x, float.y) != float.origin {
+                                                float.base.active_canvas_mut().paste(&float.pixels, float.x, float.y);
+                                                layers = float.base;
+                                                selection = outline.filter(|s| !s.is_empty());
+                                                history.commit(&layers, "Move selection");
                                             }
+                                            if !lasso_points.is_empty() {
+                                                let lasso = lasso_selection(layers.width, layers.height, &lasso_points);
+                                                selection = Selection::combine(base_selection.as_ref(), lasso, drag_op);
+                                                lasso_points.clear();
+                                            }
+                                            drag_start = None;
+                                            base_selection = None;
                                         }
                                         _ => {}
                                     }
                                 }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('r'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('R'),
+                                    ..
+                                }) => {
+                                    tool = SelectTool::Rect;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('w'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('W'),
+                                    ..
+                                }) => {
+                                    tool = SelectTool::Wand;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('l'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('L'),
+                                    ..
+                                }) => {
+                                    tool = SelectTool::Lasso;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('n'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('N'),
+                                    ..
+                                }) => {
+                                    mode_op = SelectionOp::Replace;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('u'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('U'),
+                                    ..
+                                }) => {
+                                    mode_op = SelectionOp::Add;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('s'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('S'),
+                                    ..
+                                }) => {
+                                    mode_op = SelectionOp::Subtract;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('i'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('I'),
+                                    ..
+                                }) => {
+                                    mode_op = SelectionOp::Intersect;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('g'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('G'),
+                                    ..
+                                }) => {
+                                    contiguous = !contiguous;
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Char('t'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('T'),
+                                    ..
+                                }) => {
+                                    execute!(io::stdout(), DisableMouseCapture)?;
+                                    if let Some(t) = prompt_tolerance("Wand", &mut status_message) {
+                                        tolerance = t;
+                                    }
+                                    execute!(io::stdout(), EnableMouseCapture)?;
+                                    terminal.clear()?;
+                                }
                                 Event::Key(KeyEvent {
                                     code: KeyCode::Esc,
                                     ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('a'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('A'),
+                                    ..
                                 }) => {
-                                    break 'shape_loop;
+                                    break 'select_loop;
                                 }
                                 _ => {}
                             }
                         }
                     }
+                    viewport.markers.clear();
                     execute!(io::stdout(), DisableMouseCapture)?;
                     clear_input_buffer();
                     terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('l'),
+                    code: KeyCode::Char('u'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('L'),
+                    code: KeyCode::Char('U'),
                     ..
                 }) => {
-                    execute!(io::stdout(), EnableMouseCapture)?;
-                    let mut start_pos: Option<(i32, i32)> = None;
+                    let rows = history.rows();
+                    let mut selected = rows.iter().position(|r| r.id == history.current).unwrap_or(0);
+                    let mut preview = layers.clone();
 
-                    'line_loop: loop {
+                    'history_loop: loop {
                         terminal.draw(|f| {
                             let chunks = Layout::default()
                                 .direction(Direction::Vertical)
                                 .margin(0)
-                                .constraints([Constraint::Min(1), Constraint::Length(3)])
+                                .constraints([Constraint::Min(1), Constraint::Length(2)])
                                 .split(f.size());
 
-                            let canvas_spans = canvas.render_to_spans();
+                            let areas = split_canvas_area(chunks[0], &palette);
+                            viewport.area = areas[0];
+                            let canvas_spans = viewport.render(&preview, None);
                             let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
-                            f.render_widget(canvas_widget, chunks[0]);
+                            f.render_widget(canvas_widget, areas[0]);
+                            f.render_widget(history_panel(&history, &rows, selected, areas[1].height), areas[1]);
+                            palette.area = areas[2];
+                            f.render_widget(palette.panel(current_color, secondary_color), areas[2]);
 
-                            let info = if start_pos.is_some() {
-                                Paragraph::new("[LINE] Click endpoint or press ESC to cancel.").block(Block::default().borders(Borders::TOP))
-                            } else {
-                                Paragraph::new("[LINE] Click startpoint. Press ESC to cancel.").block(Block::default().borders(Borders::TOP))
-                            };
+                            let info = Paragraph::new("[HISTORY] Up/Down to preview a step, Enter to jump there (switches branch), ESC to cancel.")
+                                .block(Block::default().borders(Borders::TOP));
                             f.render_widget(info, chunks[1]);
                         })?;
 
-                        if event::poll(Duration::from_millis(50))? {
-                            match event::read()? {
-                                Event::Mouse(mouse_event) => {
-                                    use crossterm::event::MouseEventKind;
-
-                                    if matches!(mouse_event.kind, MouseEventKind::Down(_)) {
-                                        let col = (mouse_event.column / 2) as i32;
-                                        let row = mouse_event.row as i32;
-
-                                        if let Some((sx, sy)) = start_pos {
-                                            draw_line(&mut canvas, sx, sy, col, row, current_color);
-                                            canvas_history.truncate(history_index + 1);
-                                            canvas_history.push(canvas.clone_for_preview());
-                                            history_index = canvas_history.len() - 1;
-                                            start_pos = None;
-                                            break 'line_loop;
-                                        } else {
-                                            start_pos = Some((col, row));
-                                        }
+                        if viewport.poll_event(Duration::from_millis(200))? {
+                            let ev = viewport.read_event()?;
+                            let moved = match ev {
+                                Event::Key(KeyEvent { code: KeyCode::Up, .. }) => {
+                                    selected = selected.saturating_sub(1);
+                                    true
+                                }
+                                Event::Key(KeyEvent { code: KeyCode::Down, .. }) => {
+                                    selected = (selected + 1).min(rows.len() - 1);
+                                    true
+                                }
+                                Event::Key(KeyEvent {
+                                    code: KeyCode::Enter,
+                                    ..
+                                }) => {
+                                    if let Some(state) = history.jump(rows[selected].id) {
+                                        layers = state.clone();
+                                        viewport.clamp_offset(layers.width, layers.height);
                                     }
+                                    break 'history_loop;
                                 }
                                 Event::Key(KeyEvent {
                                     code: KeyCode::Esc,
                                     ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('u'),
+                                    ..
+                                })
+                                | Event::Key(KeyEvent {
+                                    code: KeyCode::Char('U'),
+                                    ..
                                 }) => {
-                                    break 'line_loop;
+                                    break 'history_loop;
                                 }
-                                _ => {}
+                                _ => {
+                                    viewport.handle_event(&ev, preview.width, preview.height);
+                                    false
+                                }
+                            };
+                            if moved && let Some(state) = history.preview(rows[selected].id) {
+                                preview = state;
+                                viewport.clamp_offset(preview.width, preview.height);
                             }
                         }
                     }
-                    execute!(io::stdout(), DisableMouseCapture)?;
-                    clear_input_buffer();
+                    viewport.clamp_offset(layers.width, layers.height);
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('i'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('I'),
+                    ..
+                }) => {
+                    viewport.toggle_cursor(layers.width, layers.height);
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('n'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('N'),
+                    ..
+                }) => {
+                    layers.add_layer();
+                    history.commit(&layers, "New layer");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('x'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('X'),
+                    ..
+                }) if layers.delete_layer() => {
+                    history.commit(&layers, "Delete layer");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('d'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('D'),
+                    ..
+                }) => {
+                    layers.duplicate_layer();
+                    history.commit(&layers, "Duplicate layer");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('m'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('M'),
+                    ..
+                }) if layers.merge_down() => {
+                    history.commit(&layers, "Merge down");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('>'),
+                    ..
+                }) if layers.move_layer_up() => {
+                    history.commit(&layers, "Move layer up");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('<'),
+                    ..
+                }) if layers.move_layer_down() => {
+                    history.commit(&layers, "Move layer down");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Tab,
+                    ..
+                }) => {
+                    layers.active = (layers.active + 1) % layers.layers.len();
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::BackTab,
+                    ..
+                }) => {
+                    layers.active = (layers.active + layers.layers.len() - 1) % layers.layers.len();
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('v'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('V'),
+                    ..
+                }) => {
+                    let layer = layers.active_layer_mut();
+                    layer.visible = !layer.visible;
+                    history.commit(&layers, "Toggle visibility");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('k'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('K'),
+                    ..
+                }) => {
+                    let layer = layers.active_layer_mut();
+                    layer.locked = !layer.locked;
+                    history.commit(&layers, "Toggle lock");
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('o'),
+                    ..
+                })
+                | Event::Key(KeyEvent {
+                    code: KeyCode::Char('O'),
+                    ..
+                }) => {
+                    let input = prompt("Layer opacity (0-100): ");
+                    if let Ok(o) = input.parse::<usize>() {
+                        layers.active_layer_mut().opacity = clamp(o, 0, 100) as u8;
+                        history.commit(&layers, "Layer opacity");
+                    }
                     terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('f'),
+                    code: KeyCode::Char('r'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('F'),
+                    code: KeyCode::Char('R'),
                     ..
                 }) => {
-                    execute!(io::stdout(), EnableMouseCapture)?;
-                    'fill_loop: loop {
-                        terminal.draw(|f| {
-                            let chunks = Layout::default()
-                                .direction(Direction::Vertical)
-                                .margin(0)
-                                .constraints([Constraint::Min(1), Constraint::Length(2)])
-                                .split(f.size());
-
-                            let canvas_spans = canvas.render_to_spans();
-                            let canvas_widget = Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
-                            f.render_widget(canvas_widget, chunks[0]);
-
-                            let info = Paragraph::new("[FILL] Click to fill area. Press ESC to cancel.")
-                                .block(Block::default().borders(Borders::TOP));
-                            f.render_widget(info, chunks[1]);
-                        })?;
-
-                        if event::poll(Duration::from_millis(50))? {
-                            match event::read()? {
-                                Event::Mouse(mouse_event) => {
-                                    use crossterm::event::MouseEventKind;
-
-                                    if matches!(mouse_event.kind, MouseEventKind::Down(_)) {
-                                        let col = (mouse_event.column / 2) as i32;
-                                        let row = mouse_event.row as i32;
-                                        flood_fill(&mut canvas, col, row, current_color);
-                                        canvas_history.truncate(history_index + 1);
-                                        canvas_history.push(canvas.clone_for_preview());
-                                        history_index = canvas_history.len() - 1;
-                                        break 'fill_loop;
-                                    }
-                                }
-                                Event::Key(KeyEvent {
-                                    code: KeyCode::Esc,
-                                    ..
-                                }) => {
-                                    break 'fill_loop;
-                                }
-                                _ => {}
-                            }
-                        }
+                    let name = prompt("Layer name: ");
+                    if !name.is_empty() {
+                        layers.active_layer_mut().name = name;
+                        history.commit(&layers, "Rename layer");
                     }
-                    execute!(io::stdout(), DisableMouseCapture)?;
-                    clear_input_buffer();
                     terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('p'),
+                    code: KeyCode::Char('g'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('P'),
+                    code: KeyCode::Char('G'),
                     ..
                 }) => {
-                    execute!(io::stdout(), EnableMouseCapture)?;
-                    let mut last_pos: Option<(i32, i32)> = None;
-                    'paint_loop: loop {
-                        terminal.draw(|f| {
-                            let chunks = Layout::default()
-                                .direction(Direction::Vertical)
-                                .margin(0)
-                                .constraints([Constraint::Min(1), Constraint::Length(2)])
-                                .split(f.size());
-
-                            let canvas_spans = canvas.render_to_spans();
-                            let canvas_widget =
-                                Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
-                            f.render_widget(canvas_widget, chunks[0]);
-
-                            let info = Paragraph::new("[PAINT MODE] Click/drag to draw. Press ESC or P to exit.")
-                                .block(Block::default().borders(Borders::TOP));
-                            f.render_widget(info, chunks[1]);
-                        })?;
-
-                        if event::poll(Duration::from_millis(50))? {
-                            match event::read()? {
-                                Event::Mouse(mouse_event) => {
-                                    use crossterm::event::MouseEventKind;
-                                    
-                                    match mouse_event.kind {
-                                        MouseEventKind::Drag(_) => {
-                                            let col = (mouse_event.column / 2) as i32;
-                                            let row = mouse_event.row as i32;
-
-                                            if let Some((last_x, last_y)) = last_pos {
-                                                draw_line_with_brush(&mut canvas, last_x, last_y, col, row, brush_thickness, current_color);
-                                            } else {
-                                                draw_brush_stroke(&mut canvas, col as usize, row as usize, brush_thickness, current_color);
-                                            }
-                                            last_pos = Some((col, row));
-                                        }
-                                        MouseEventKind::Up(_) => {
-                                            last_pos = None;
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                                Event::Key(KeyEvent {
-                                    code: KeyCode::Esc,
-                                    ..
-                                })
-                                | Event::Key(KeyEvent {
-                                    code: KeyCode::Char('p'),
-                                    ..
-                                })
-                                | Event::Key(KeyEvent {
-                                    code: KeyCode::Char('P'),
-                                    ..
-                                }) => {
-                                    execute!(io::stdout(), DisableMouseCapture)?;
-                                    clear_input_buffer();
-                                    canvas_history.truncate(history_index + 1);
-                                    canvas_history.push(canvas.clone_for_preview());
-                                    history_index = canvas_history.len() - 1;
-                                    terminal.clear()?;
-                                    break 'paint_loop;
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
+                    palette.add(current_color);
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('e'),
+                    code: KeyCode::Char('b'),
                     ..
                 })
                 | Event::Key(KeyEvent {
-                    code: KeyCode::Char('E'),
+                    code: KeyCode::Char('B'),
                     ..
                 }) => {
-                    execute!(io::stdout(), EnableMouseCapture)?;
-                    let mut last_pos: Option<(i32, i32)> = None;
-                    'erase_loop: loop {
-                        terminal.draw(|f| {
-                            let chunks = Layout::default()
-                                .direction(Direction::Vertical)
-                                .margin(0)
-                                .constraints([Constraint::Min(1), Constraint::Length(2)])
-                                .split(f.size());
-
-                            let canvas_spans = canvas.render_to_spans();
-                            let canvas_widget =
-                                Paragraph::new(canvas_spans).block(Block::default().borders(Borders::NONE));
-                            f.render_widget(canvas_widget, chunks[0]);
+                    palette.replace_selected(current_color);
+                }
 
-                            let info = Paragraph::new("[ERASER MODE] Click/drag to erase. Press ESC or E to exit.")
-                                .block(Block::default().borders(Borders::TOP));
-                            f.render_widget(info, chunks[1]);
-                        })?;
+                Event::Key(KeyEvent {
+                    code: KeyCode::Delete,
+                    ..
+                }) => {
+                    palette.remove_selected();
+                }
 
-                        if event::poll(Duration::from_millis(50))? {
-                            match event::read()? {
-                                Event::Mouse(mouse_event) => {
-                                    use crossterm::event::MouseEventKind;
-                                    
-                                    match mouse_event.kind {
-                                        MouseEventKind::Drag(_) => {
-                                            let col = (mouse_event.column / 2) as i32;
-                                            let row = mouse_event.row as i32;
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('#'),
+                    ..
+                }) => {
+                    match canvas_palette(&layers.composite()) {
+                        Ok(colors) if colors.is_empty() => {
+                            prompt("The canvas is fully transparent. Press Enter to continue...");
+                        }
+                        Ok(colors) => palette = Palette::from_colors(colors),
+                        Err(count) => {
+                            prompt(&format!(
+                                "The canvas has {} colours; palettes hold at most {}. Press Enter to continue...",
+                                count, PALETTE_MAX_COLORS
+                            ));
+                        }
+                    }
+                    terminal.clear()?;
+                }
 
-                                            if let Some((last_x, last_y)) = last_pos {
-                                                draw_line_with_brush(&mut canvas, last_x, last_y, col, row, brush_thickness, [255, 255, 255]);
-                                            } else {
-                                                draw_brush_stroke(&mut canvas, col as usize, row as usize, brush_thickness, [255, 255, 255]);
-                                            }
-                                            last_pos = Some((col, row));
-                                        }
-                                        MouseEventKind::Up(_) => {
-                                            last_pos = None;
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                                Event::Key(KeyEvent {
-                                    code: KeyCode::Esc,
-                                    ..
-                                })
-                                | Event::Key(KeyEvent {
-                                    code: KeyCode::Char('e'),
-                                    ..
-                                })
-                                | Event::Key(KeyEvent {
-                                    code: KeyCode::Char('E'),
-                                    ..
-                                }) => {
-                                    execute!(io::stdout(), DisableMouseCapture)?;
-                                    clear_input_buffer();
-                                    canvas_history.truncate(history_index + 1);
-                                    canvas_history.push(canvas.clone_for_preview());
-                                    history_index = canvas_history.len() - 1;
-                                    terminal.clear()?;
-                                    break 'erase_loop;
-                                }
-                                _ => {}
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('}'),
+                    ..
+                }) => {
+                    let filename = prompt("Open palette file (.gpl, .pal or .hex): ");
+                    if !filename.trim().is_empty() {
+                        match load_palette(filename.trim()) {
+                            Ok(colors) => palette = Palette::from_colors(colors),
+                            Err(e) => {
+                                prompt(&format!("Error loading palette: {}. Press Enter to continue...", e));
                             }
                         }
                     }
+                    terminal.clear()?;
                 }
 
                 Event::Key(KeyEvent {
-                    code: KeyCode::Char('['),
+                    code: KeyCode::Char('{'),
                     ..
                 }) => {
-                    let filename = prompt("Export filename (without .rai): ");
+                    let filename = prompt("Export palette to (.gpl, .pal or .hex): ");
                     if !filename.trim().is_empty() {
-                        let filename = filename.trim();
-                        let filepath = if filename.ends_with(".rai") {
-                            filename.to_string()
-                        } else {
-                            format!("{}.rai", filename)
+                        let message = match save_palette(filename.trim(), &palette.colors) {
+                            Ok(()) => format!("Palette exported to: {}", expand_path(filename.trim())),
+                            Err(e) => format!("Error saving palette: {}", e),
                         };
-                        match save_canvas(&canvas, &filepath) {
+                        prompt(&format!("{}. Press Enter to continue...", message));
+                    }
+                    terminal.clear()?;
+                }
+
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('['),
+                    ..
+                }) => {
+                    let filename = prompt("Export filename (.rai by default, or .png): ");
+                    if !filename.trim().is_empty() {
+                        let filepath = with_default_extension(filename.trim());
+                        match save_document(&layers, &filepath, &palette, Some(&history), history_storage) {
                             Ok(_) => {
+                                autosave.saved(&history, &palette.colors);
                                 disable_raw_mode()?;
                                 let expanded = expand_path(&filepath);
                                 println!("Image exported to: {}", expanded);
@@ -989,14 +7114,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     code: KeyCode::Char(']'),
                     ..
                 }) => {
-                    let filename = prompt("Open .rai file (with .rai extension): ");
+                    let filename = prompt("Open .rai or .png file (with extension): ");
                     if !filename.trim().is_empty() {
-                        match load_canvas(filename.trim()) {
-                            Ok(loaded_canvas) => {
-                                canvas = loaded_canvas;
-                                canvas_history.truncate(history_index + 1);
-                                canvas_history.push(canvas.clone_for_preview());
-                                history_index = canvas_history.len() - 1;
+                        match load_document(filename.trim()) {
+                            Ok(doc) => {
+                                layers = doc.layers;
+                                if let Some(colors) = doc.palette {
+                                    palette = Palette::from_colors(colors);
+                                }
+                                selection = None;
+                                save_target = Some(filename.trim().to_string());
+                                viewport.clamp_offset(layers.width, layers.height);
+                                let restored = doc
+                                    .history
+                                    .and_then(|body| decode_history(&body, &layers, options.undo_steps, undo_budget).ok());
+                                match restored {
+                                    Some(restored) => {
+                                        history = restored;
+                                        history_storage = options.save_history.unwrap_or(doc.storage);
+                                    }
+                                    None => history.commit(&layers, "Open file"),
+                                }
+                                autosave.saved(&history, &palette.colors);
                                 disable_raw_mode()?;
                                 println!("Image loaded successfully!");
                                 let _ = io::stdout().flush();
@@ -1019,16 +7158,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     code: KeyCode::Char('*'),
                     ..
                 }) => {
-                    let filename = prompt("Save to existing .rai file (path): ");
+                    let filename = match &save_target {
+                        Some(target) => target.clone(),
+                        None => prompt("Save to existing .rai or .png file (path): "),
+                    };
                     if !filename.trim().is_empty() {
-                        let filename = filename.trim();
-                        let filepath = if filename.ends_with(".rai") {
-                            filename.to_string()
-                        } else {
-                            format!("{}.rai", filename)
-                        };
-                        match save_canvas(&canvas, &filepath) {
+                        let filepath = with_default_extension(filename.trim());
+                        match save_document(&layers, &filepath, &palette, Some(&history), history_storage) {
                             Ok(_) => {
+                                save_target = Some(filepath.clone());
+                                autosave.saved(&history, &palette.colors);
                                 disable_raw_mode()?;
                                 let expanded = expand_path(&filepath);
                                 println!("File saved: {}", expanded);
@@ -1053,8 +7192,293 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    autosave.discard();
     disable_raw_mode()?;
     execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
     println!("Thanks for using the ASCII Image Editor!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small xorshift generator so the random cases are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    // The stack-based fill that `connected_region` replaced, kept as the reference
+    fn reference_region(canvas: &Canvas, x: i32, y: i32, tolerance: u8, diagonal: bool, within: Option<&Selection>) -> Selection {
+        let w = canvas.width as i32;
+        let h = canvas.height as i32;
+        let mut region = Selection::empty(canvas.width, canvas.height);
+        if x < 0 || y < 0 || x >= w || y >= h || within.is_some_and(|s| !s.contains(x, y)) {
+            return region;
+        }
+
+        let target = canvas.get_pixel(x as usize, y as usize);
+        let mut stack = vec![(x, y)];
+        while let Some((cx, cy)) = stack.pop() {
+            if cx < 0 || cy < 0 || cx >= w || cy >= h {
+                continue;
+            }
+            let i = (cy * w + cx) as usize;
+            if region.mask[i] || !colors_match(canvas.pixels[i], target, tolerance) || within.is_some_and(|s| !s.mask[i]) {
+                continue;
+            }
+            region.mask[i] = true;
+            stack.extend([(cx + 1, cy), (cx - 1, cy), (cx, cy + 1), (cx, cy - 1)]);
+            if diagonal {
+                stack.extend([(cx + 1, cy + 1), (cx - 1, cy + 1), (cx + 1, cy - 1), (cx - 1, cy - 1)]);
+            }
+        }
+        region
+    }
+
+    // A few base colours plus slight variations of them, so tolerance matters
+    fn random_canvas(rng: &mut Rng, width: usize, height: usize) -> Canvas {
+        let mut canvas = Canvas::new(width, height);
+        let colors = 1 + rng.below(4) as u8;
+        let density = rng.below(101);
+        for pixel in canvas.pixels.iter_mut() {
+            if rng.below(100) < density {
+                let k = rng.below(colors as u64) as u8;
+                *pixel = [k * 60, k * 60 + rng.below(6) as u8, 0, 255];
+            }
+        }
+        canvas
+    }
+
+    #[test]
+    fn scanline_fill_matches_reference() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for case in 0..3000 {
+            let (w, h) = (1 + rng.below(40) as usize, 1 + rng.below(40) as usize);
+            let canvas = random_canvas(&mut rng, w, h);
+            let within = (rng.below(3) == 0).then(|| {
+                let mut selection = Selection::empty(w, h);
+                selection.mask.iter_mut().for_each(|m| *m = rng.below(100) < 80);
+                selection
+            });
+            let tolerance = [0, 1, 3, 15, 100][rng.below(5) as usize];
+            let diagonal = rng.below(2) == 0;
+            // Seeds just outside the canvas must give an empty region
+            let x = rng.below(w as u64 + 2) as i32 - 1;
+            let y = rng.below(h as u64 + 2) as i32 - 1;
+
+            let expected = reference_region(&canvas, x, y, tolerance, diagonal, within.as_ref());
+            let region = connected_region(&canvas, x, y, tolerance, diagonal, within.as_ref());
+            assert!(region.mask == expected.mask, "case {}: {}x{} from {},{} tolerance {} diagonal {}", case, w, h, x, y, tolerance, diagonal);
+
+            let color = [1, 2, 3, 255];
+            let mut filled = canvas.clone();
+            flood_fill(&mut filled, x, y, color, within.as_ref(), FillOptions { tolerance, diagonal, global: false });
+            let mut reference = canvas.clone();
+            for (pixel, &inside) in reference.pixels.iter_mut().zip(&expected.mask) {
+                if inside {
+                    *pixel = color;
+                }
+            }
+            assert!(filled.pixels == reference.pixels, "case {}: flood_fill differs from the reference", case);
+        }
+    }
+
+    #[test]
+    fn loaded_layers_keep_unique_names() {
+        let layer = |name: &str| Layer::new(name, Canvas::new(2, 2));
+        let mut layers = LayerStack::from_layers(2, 2, vec![layer("Background"), layer("Layer 3"), layer("Layer x")], 0);
+        layers.add_layer();
+        assert_eq!(layers.active_layer().name, "Layer 4");
+    }
+
+    fn history_body(history: &History) -> Vec<u8> {
+        let raw = encode_history(history);
+        let mut body = (raw.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(&zlib_compress(&raw));
+        body
+    }
+
+    // A branched tree: two pixel edits, an undo, a third edit and a new layer
+    fn sample_history() -> (History, LayerStack) {
+        let mut layers = LayerStack::new(4, 4);
+        let mut history = History::new(&layers, 100, 1 << 20);
+        for (x, label) in [(0, "a"), (1, "b")] {
+            layers.active_canvas_mut().set_pixel(x, 0, [0, 0, 0, 255]);
+            history.commit(&layers, label);
+        }
+        layers = history.undo().unwrap().clone();
+        layers.active_canvas_mut().set_pixel(2, 2, [9, 9, 9, 255]);
+        history.commit(&layers, "c");
+        layers.add_layer();
+        history.commit(&layers, "New layer");
+        (history, layers)
+    }
+
+    #[test]
+    fn saved_history_round_trips() {
+        let (history, layers) = sample_history();
+        let mut restored = decode_history(&history_body(&history), &layers, 100, 1 << 20).ok().unwrap();
+        assert_eq!(restored.rows().len(), history.rows().len());
+        for id in history.nodes.keys().copied() {
+            let expected = history.preview(id).unwrap().composite().pixels;
+            assert!(restored.jump(id).unwrap().composite().pixels == expected);
+        }
+    }
+
+    #[test]
+    fn corrupt_history_is_rejected() {
+        let (history, layers) = sample_history();
+        let ids: Vec<usize> = history.nodes.keys().copied().collect();
+
+        let mut looped = sample_history().0;
+        looped.nodes.get_mut(&ids[1]).unwrap().parent = Some(ids[2]);
+        let mut dangling = sample_history().0;
+        dangling.nodes.get_mut(&ids[0]).unwrap().redo_child = Some(999);
+        let mut stranger = sample_history().0;
+        stranger.nodes.get_mut(&ids[0]).unwrap().redo_child = Some(ids[4]);
+        for (name, broken) in [("parent loop", &looped), ("missing redo child", &dangling), ("redo to a grandchild", &stranger)] {
+            assert!(decode_history(&history_body(broken), &layers, 100, 1 << 20).is_err(), "{}", name);
+        }
+
+        let mut edited = layers.clone();
+        edited.layers[0].canvas.set_pixel(2, 2, [1, 2, 3, 255]);
+        assert!(decode_history(&history_body(&history), &edited, 100, 1 << 20).is_err(), "edited pixels");
+
+        let mut huge = history_body(&history);
+        huge[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_history(&huge, &layers, 100, 1 << 20).is_err(), "oversized history");
+    }
+
+    #[test]
+    fn soft_brush_blends_like_one_stroke() {
+        // Wanders in every direction so the coverage window has to grow on all sides
+        let path = [(30, 30), (34, 31), (38, 20), (20, 12), (5, 25), (12, 50), (45, 58), (59, 40), (40, 0)];
+        let base = Canvas::new(60, 60);
+        let color = [200, 20, 90, 180];
+
+        let mut once = base.clone();
+        let mut coverage = Coverage::new(60, 60);
+        for pair in path.windows(2) {
+            coverage.stroke(pair[0], pair[1], 5);
+        }
+        coverage.apply(&mut once, None, color);
+
+        let mut dragged = base.clone();
+        let mut coverage = Coverage::new(60, 60);
+        for pair in path.windows(2) {
+            coverage.stroke(pair[0], pair[1], 5);
+            coverage.apply(&mut dragged, Some(&base), color);
+        }
+        assert!(dragged.pixels == once.pixels);
+
+        // Only the stroke's surroundings are stored, however big the canvas
+        let mut small = Coverage::new(4096, 4096);
+        small.stroke((10, 10), (20, 12), 3);
+        assert!(small.values.len() < 1000);
+    }
+
+    #[test]
+    fn palette_edits_reach_the_recovery_file() {
+        let layers = LayerStack::new(4, 4);
+        let history = History::new(&layers, 10, 1 << 20);
+        let mut palette = Palette::new();
+        let mut autosave = Autosave::new(&layers, &history, &palette.colors, None, false);
+        palette.add([1, 2, 3, 255]);
+        autosave.tick(&layers, &history, &palette.colors, None);
+        let snapshot = autosave.snapshot.lock().unwrap();
+        assert!(snapshot.unsaved);
+
+        let path = std::env::temp_dir().join(format!("raint-test-{}.rai", std::process::id()));
+        write_recovery_file(&path, &snapshot.layers, &snapshot.palette, None).unwrap();
+        let doc = decode_rai(&std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(doc.ok().and_then(|doc| doc.palette), Some(palette.colors));
+    }
+
+    #[test]
+    fn oversized_palette_files_are_rejected() {
+        let path = std::env::temp_dir().join(format!("raint-test-{}.hex", std::process::id()));
+        let path = path.to_str().unwrap();
+        let lines: Vec<String> = (0..=PALETTE_MAX_COLORS).map(|i| format!("{:06x}", i)).collect();
+        std::fs::write(path, lines[..PALETTE_MAX_COLORS].join("\n")).unwrap();
+        assert_eq!(load_palette(path).map(|colors| colors.len()).ok(), Some(PALETTE_MAX_COLORS));
+        std::fs::write(path, lines.join("\n")).unwrap();
+        let error = load_palette(path).unwrap_err().to_string();
+        let _ = std::fs::remove_file(path);
+        assert!(error.contains("at most 256"), "{}", error);
+    }
+
+    #[test]
+    fn rai_files_larger_than_the_editor_are_rejected() {
+        for (width, height, ok) in [(MAX_CANVAS_SIZE, 2, true), (MAX_CANVAS_SIZE + 1, 2, false), (2, MAX_CANVAS_SIZE + 1, false)] {
+            let data = encode_rai(&LayerStack::new(width, height), &[], None, None);
+            assert_eq!(decode_rai(&data).is_ok(), ok, "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn tolerance_is_a_percentage_of_the_largest_distance() {
+        let (black, white, clear) = ([0, 0, 0, 255], [255, 255, 255, 255], [255, 255, 255, 0]);
+        assert!(colors_match(black, black, 0));
+        assert!(!colors_match(black, [1, 0, 0, 255], 0));
+        assert!(!colors_match(black, white, 86));
+        assert!(colors_match(black, white, 87));
+        assert!(!colors_match(black, clear, 99));
+        assert!(colors_match(black, clear, 100));
+    }
+
+    #[test]
+    fn batch_rejects_far_coordinates() {
+        for script in ["size 8 8\ncircle 4 4 50000", "size 8 8\nline 0 0 2000000000 0", "size 8 8\nrect 4 4 -1"] {
+            let error = run_batch(script, None).err().expect(script);
+            assert_eq!(error.line, 2, "{}", script);
+        }
+        let canvas = run_batch("size 8 8\ncircle 4 4 8000\nline -8000 0 8000 7", None).ok().flatten().unwrap();
+        assert!(canvas.pixels.iter().all(|&p| p == [0, 0, 0, 255]));
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_flood_fill_2048() {
+        let n = 2048;
+        let blank = Canvas::new(n, n);
+        let mut rng = Rng(42);
+        let mut noise = Canvas::new(n, n);
+        noise.pixels.iter_mut().filter(|_| rng.below(100) < 35).for_each(|p| *p = [0, 0, 0, 255]);
+        // Vertical walls every 4 pixels with a staggered gap, so the fill snakes through
+        let mut serpentine = Canvas::new(n, n);
+        for y in 0..n {
+            for x in (1..n).step_by(4) {
+                if (y + 2 * (x / 4)) % n != 0 {
+                    serpentine.set_pixel(x, y, [0, 0, 0, 255]);
+                }
+            }
+        }
+
+        for (name, canvas) in [("blank", &blank), ("35% noise", &noise), ("serpentine", &serpentine)] {
+            for diagonal in [false, true] {
+                let start = std::time::Instant::now();
+                let region = connected_region(canvas, 0, 0, 0, diagonal, None);
+                let scanline = start.elapsed();
+                let start = std::time::Instant::now();
+                let expected = reference_region(canvas, 0, 0, 0, diagonal, None);
+                let reference = start.elapsed();
+                assert!(region.mask == expected.mask);
+                println!("{:<12} {}-connected: scanline {:>8.1?}, stack {:>8.1?}", name, if diagonal { 8 } else { 4 }, scanline, reference);
+            }
+        }
+    }
+}